@@ -0,0 +1,142 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, LitStr, Variant};
+
+/// Derives `MaelstromMessage` for an enum with one variant per message kind.
+///
+/// The kind of a variant is its name in snake case, e.g. `cas_request` for `CASRequest`, unless overridden with
+/// `#[maelstrom(kind = "...")]`.
+/// Unit variants ignore the message content, newtype variants deserialize the whole content into their
+/// field, and struct variants deserialize each of their fields from the content field of the same name.
+#[proc_macro_derive(MaelstromMessage, attributes(maelstrom))]
+pub fn derive_maelstrom_message(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Enum(data) = &input.data else {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "MaelstromMessage can only be derived for enums",
+        ));
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut kinds = Vec::new();
+    let mut arms = Vec::new();
+
+    for variant in &data.variants {
+        let kind = variant_kind(variant)?;
+        if kinds.contains(&kind) {
+            return Err(Error::new_spanned(
+                variant,
+                format!("message type `{kind}` is used by more than one variant"),
+            ));
+        }
+
+        let ident = &variant.ident;
+        let value = match &variant.fields {
+            Fields::Unit => quote! { Self::#ident },
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+                quote! { Self::#ident(content.decode()?) }
+            }
+            Fields::Unnamed(fields) => {
+                return Err(Error::new_spanned(
+                    fields,
+                    "tuple variants must have exactly one field",
+                ))
+            }
+            Fields::Named(fields) => {
+                let fields = fields.named.iter().map(|field| {
+                    let field = &field.ident;
+                    let key = field.as_ref().map(|f| f.to_string()).unwrap_or_default();
+                    quote! { #field: content.decode_field(#key)? }
+                });
+                quote! { Self::#ident { #(#fields),* } }
+            }
+        };
+
+        arms.push(quote! { #kind => ::core::result::Result::Ok(#value), });
+        kinds.push(kind);
+    }
+
+    let protocol = quote! { ::gossip_glomers_rust::protocol };
+
+    Ok(quote! {
+        impl #impl_generics #protocol::MaelstromMessage for #name #ty_generics #where_clause {
+            const KINDS: &'static [&'static str] = &[#(#kinds),*];
+
+            fn from_content(
+                content: &#protocol::MessageContent,
+            ) -> ::core::result::Result<Self, #protocol::ErrorMessage> {
+                match content.kind.as_str() {
+                    #(#arms)*
+                    kind => ::core::result::Result::Err(#protocol::ErrorMessage::new(
+                        #protocol::ErrorKind::NotSupported,
+                        &::std::format!("message type {kind} not supported"),
+                    )),
+                }
+            }
+        }
+    })
+}
+
+fn variant_kind(variant: &Variant) -> syn::Result<String> {
+    let mut kind = None;
+
+    for attr in variant
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("maelstrom"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("kind") {
+                kind = Some(meta.value()?.parse::<LitStr>()?.value());
+                Ok(())
+            } else {
+                Err(meta.error("unsupported maelstrom attribute"))
+            }
+        })?;
+    }
+
+    Ok(kind.unwrap_or_else(|| to_snake_case(&variant.ident.to_string())))
+}
+
+/// Treats a run of capitals as one word, so that `CASRequest` becomes `cas_request`.
+fn to_snake_case(name: &str) -> String {
+    let chars = name.chars().collect::<Vec<_>>();
+    let mut snake = String::with_capacity(name.len() + 4);
+
+    for (i, c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).is_some_and(|next| next.is_lowercase());
+            if !prev.is_uppercase() || next_is_lower {
+                snake.push('_');
+            }
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_snake_case() {
+        assert_eq!(to_snake_case("Echo"), "echo");
+        assert_eq!(to_snake_case("ReadOk"), "read_ok");
+        assert_eq!(to_snake_case("CASRequest"), "cas_request");
+        assert_eq!(to_snake_case("Cas"), "cas");
+        assert_eq!(to_snake_case("TxnKV"), "txn_kv");
+        assert_eq!(to_snake_case("Read2Ok"), "read2_ok");
+    }
+}
@@ -0,0 +1,5 @@
+extern crate self as gossip_glomers_rust; // Lets `#[derive(MaelstromMessage)]` be used within the crate.
+
+pub mod messages;
+pub mod protocol;
+pub mod server;
@@ -1,29 +1,79 @@
-#![feature(try_trait_v2)]
+use gossip_glomers_rust::{
+    messages::{EchoMessageHandler, GenerateIdMessageHandler},
+    server::{LineReader, MaelstromService, PreInitPolicy, DEFAULT_MAX_LINE_SIZE},
+};
+use std::{
+    process::ExitCode,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
 
-use messages::{EchoMessageHandler, GenerateIdMessageHandler};
-use serde_json::{de::StrRead, Deserializer};
-use server::MaelstromService;
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
 
-mod messages;
-mod protocol;
-mod server;
-
-fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<ExitCode> {
     let mut server = MaelstromService::new();
     server.register_handler::<EchoMessageHandler>();
     server.register_handler::<GenerateIdMessageHandler>();
+    server.set_pre_init_policy(PreInitPolicy::Reject);
+    server.validate()?;
+
+    // All output goes through the outbox, so a single thread writes stdout and lines never interleave
+    let outbox = server.outbox();
+    server.spawn_outbox_writer(std::io::stdout());
+
+    // Stdin is read on its own thread, so that handlers still get their ticks while no input arrives
+    let (lines_tx, lines) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = LineReader::new(std::io::stdin().lock()).with_max_line_size(max_line_size());
+        for line in stdin {
+            if lines_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
 
-    let stdin = std::io::stdin().lines();
+    loop {
+        let line = match server.tick_interval() {
+            Some(interval) => match lines.recv_timeout(interval) {
+                Ok(line) => Some(line),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+            None => match lines.recv() {
+                Ok(line) => Some(line),
+                Err(_) => break,
+            },
+        };
 
-    for line in stdin {
-        let line = line.unwrap();
-        let mut de = Deserializer::new(StrRead::new(line.as_ref()));
+        match line {
+            Some(Ok(line)) => {
+                for resp in server.input_line(&line) {
+                    outbox.post(resp)?;
+                }
+            }
+            Some(Err(err)) => {
+                for resp in server.reject_input(&err) {
+                    outbox.post(resp)?;
+                }
+            }
+            None => {}
+        }
 
-        for resp in server.input(&mut de) {
-            let ser = serde_json::to_string(&resp).unwrap();
-            println!("{}", ser);
+        for msg in server.tick(Instant::now()) {
+            outbox.post(msg)?;
         }
     }
 
-    Ok(())
+    let report = server.shutdown(SHUTDOWN_TIMEOUT);
+
+    Ok(report.exit_code())
+}
+
+/// The longest input line accepted, in bytes; configurable with `MAX_LINE_SIZE`.
+fn max_line_size() -> usize {
+    std::env::var("MAX_LINE_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(DEFAULT_MAX_LINE_SIZE)
 }
@@ -1,7 +1,10 @@
 use serde::{Deserialize, Serialize};
 
-use crate::protocol::{ErrorMessage, MessageContext, MessageHandler};
+use crate::protocol::{
+    ErrorMessage, MaelstromMessage, MessageContext, MessageHandler, Response, Rpc,
+};
 
+#[derive(Default)]
 pub struct EchoMessageHandler;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -9,30 +12,38 @@ pub struct EchoMessageContent {
     echo: String,
 }
 
-impl MessageHandler for EchoMessageHandler {
-    fn new() -> Self
-    where
-        Self: Sized,
-    {
-        EchoMessageHandler
-    }
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EchoOkMessageContent {
+    echo: String,
+}
+
+impl Rpc for EchoMessageContent {
+    const KIND: &'static str = "echo";
+    const RESPONSE_KIND: &'static str = "echo_ok";
 
-    fn get_handled_messages() -> impl Iterator<Item = &'static str>
+    type Response = EchoOkMessageContent;
+}
+
+#[derive(MaelstromMessage)]
+enum EchoRequest {
+    Echo(EchoMessageContent),
+}
+
+impl MessageHandler for EchoMessageHandler {
+    fn get_handled_messages(&self) -> impl Iterator<Item = &str>
     where
         Self: Sized,
     {
-        ["echo"].into_iter()
+        EchoRequest::KINDS.iter().copied()
     }
 
-    fn handle(&mut self, ctx: &MessageContext) -> Result<(), ErrorMessage> {
-        let msg = ctx.message_content::<EchoMessageContent>()?;
-
-        ctx.reply(
-            "echo",
-            &EchoMessageContent {
-                echo: msg.echo.clone(),
-            },
-        )
+    fn handle(&mut self, ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+        match ctx.request()? {
+            EchoRequest::Echo(msg) => {
+                Response::of::<EchoMessageContent>(&EchoOkMessageContent { echo: msg.echo })
+                    .map(Some)
+            }
+        }
     }
 }
 
@@ -69,8 +80,10 @@ mod tests {
 
         assert!(res.is_ok());
 
+        ctx.respond(res.unwrap().unwrap()).unwrap();
         let response = ctx.into_output_iter().next().unwrap();
 
+        assert_eq!(response.kind(), "echo_ok");
         assert_eq!(response.body.content.data, echo_data);
         assert_eq!(response.dest, Some("n2".to_string()));
         assert_eq!(response.body.in_reply_to, Some(123));
@@ -4,7 +4,9 @@ use uuid::{
     Uuid,
 };
 
-use crate::protocol::{ErrorKind, ErrorMessage, MessageContext, MessageHandler};
+use crate::protocol::{
+    ErrorKind, ErrorMessage, MaelstromMessage, MessageContext, MessageHandler, Response, Rpc,
+};
 
 pub struct GenerateIdMessageHandler {
     node_id: Option<[u8; 6]>,
@@ -12,38 +14,45 @@ pub struct GenerateIdMessageHandler {
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-pub struct GenerateIdMessageContent;
+pub struct GenerateIdMessageContent {}
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct GenerateIdOkMessageContent {
     id: String,
 }
 
-impl MessageHandler for GenerateIdMessageHandler {
-    fn new() -> Self
-    where
-        Self: Sized,
-    {
+impl Rpc for GenerateIdMessageContent {
+    const KIND: &'static str = "generate";
+    const RESPONSE_KIND: &'static str = "generate_ok";
+
+    type Response = GenerateIdOkMessageContent;
+}
+
+#[derive(MaelstromMessage)]
+enum GenerateIdRequest {
+    Generate,
+}
+
+impl Default for GenerateIdMessageHandler {
+    fn default() -> Self {
         Self {
             node_id: None,
             ctx: Context::new_random(),
         }
     }
+}
 
-    fn get_handled_messages() -> impl Iterator<Item = &'static str>
+impl MessageHandler for GenerateIdMessageHandler {
+    fn get_handled_messages(&self) -> impl Iterator<Item = &str>
     where
         Self: Sized,
     {
-        ["generate"].into_iter()
+        GenerateIdRequest::KINDS.iter().copied()
     }
 
-    fn handle(&mut self, ctx: &MessageContext) -> Result<(), ErrorMessage> {
-        match ctx.message_kind() {
-            "generate" => self.handle_generate_id(ctx),
-            kind => Err(ErrorMessage::new(
-                ErrorKind::NotSupported,
-                &format!("message type {kind} not supported"),
-            )),
+    fn handle(&mut self, ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+        match ctx.request()? {
+            GenerateIdRequest::Generate => self.handle_generate_id().map(Some),
         }
     }
 
@@ -71,12 +80,12 @@ impl MessageHandler for GenerateIdMessageHandler {
 }
 
 impl GenerateIdMessageHandler {
-    fn handle_generate_id(&mut self, ctx: &MessageContext) -> Result<(), ErrorMessage> {
+    fn handle_generate_id(&mut self) -> Result<Response, ErrorMessage> {
         if let Some(ref node_id) = self.node_id {
             let ts = timestamp::Timestamp::now(&self.ctx);
             let uuid = Uuid::new_v6(ts, node_id).to_string();
 
-            ctx.reply("generate_ok", &GenerateIdOkMessageContent { id: uuid })
+            Response::of::<GenerateIdMessageContent>(&GenerateIdOkMessageContent { id: uuid })
         } else {
             Err(ErrorMessage::new(
                 ErrorKind::TemporarilyUnavailable,
@@ -1,5 +1,7 @@
 mod echo;
 mod generate_id;
 
-pub use echo::EchoMessageHandler;
-pub use generate_id::GenerateIdMessageHandler;
+pub use echo::{EchoMessageContent, EchoMessageHandler, EchoOkMessageContent};
+pub use generate_id::{
+    GenerateIdMessageContent, GenerateIdMessageHandler, GenerateIdOkMessageContent,
+};
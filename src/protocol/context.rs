@@ -1,21 +1,51 @@
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     collections::VecDeque,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::Arc,
 };
 
 use serde::{de::DeserializeOwned, Serialize};
 
 use super::{
+    decode_response,
     serialization::{deserialize_message_content, serialize_message_content},
-    ErrorKind, ErrorMessage, Message, MessageBody, MessageContent,
+    ClusterMembership, DeferredInit, DeferredReplies, DeferredReply, DynamicMap, ErrorKind,
+    ErrorMessage, Extensions, InitTracker, MaelstromMessage, Message, MessageBody, MessageContent,
+    MessageIdAllocator, Outbox, PendingRequests, Response, Rpc,
 };
 
-static SHARED_MESSAGE_ID_COUNTER: AtomicUsize = AtomicUsize::new(1);
+#[derive(Clone, Copy, Debug)]
+pub enum BroadcastTargets<'a> {
+    Cluster,             // Every node listed in the `init` message.
+    Nodes(&'a [String]), // An explicit subset of the cluster, e.g. the neighbours from a topology.
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BroadcastOptions {
+    pub skip_self: bool,
+    pub skip_sender: bool,
+}
+
+impl Default for BroadcastOptions {
+    fn default() -> Self {
+        Self {
+            skip_self: true,
+            skip_sender: false,
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct MessageContext {
     msg: Option<Message>,
+    membership: Option<Arc<ClusterMembership>>,
+    outbox: Option<Outbox>,
+    msg_ids: MessageIdAllocator,
+    init: Option<InitTracker>,
+    extensions: Extensions,
+    pending: PendingRequests,
+    deferred: DeferredReplies,
+    reply_deferred: Cell<bool>,
     output: RefCell<VecDeque<Message>>,
 }
 
@@ -23,10 +53,82 @@ impl MessageContext {
     pub fn new(msg: Option<Message>) -> Self {
         Self {
             msg,
+            membership: None,
+            outbox: None,
+            msg_ids: Default::default(),
+            init: None,
+            extensions: Default::default(),
+            pending: Default::default(),
+            deferred: Default::default(),
+            reply_deferred: Default::default(),
             output: Default::default(),
         }
     }
 
+    pub fn with_membership(self, membership: Arc<ClusterMembership>) -> Self {
+        Self {
+            membership: Some(membership),
+            ..self
+        }
+    }
+
+    pub fn with_outbox(self, outbox: Outbox) -> Self {
+        Self {
+            outbox: Some(outbox),
+            ..self
+        }
+    }
+
+    pub fn with_message_ids(self, msg_ids: MessageIdAllocator) -> Self {
+        Self { msg_ids, ..self }
+    }
+
+    pub fn with_init_tracker(self, init: InitTracker) -> Self {
+        Self {
+            init: Some(init),
+            ..self
+        }
+    }
+
+    pub fn with_extensions(self, extensions: Extensions) -> Self {
+        Self { extensions, ..self }
+    }
+
+    pub fn with_pending_requests(self, pending: PendingRequests) -> Self {
+        Self { pending, ..self }
+    }
+
+    pub fn with_deferred_replies(self, deferred: DeferredReplies) -> Self {
+        Self { deferred, ..self }
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.membership.is_some()
+    }
+
+    pub fn membership(&self) -> Option<&ClusterMembership> {
+        self.membership.as_deref()
+    }
+
+    pub fn node_id(&self) -> Option<&str> {
+        self.membership.as_ref().map(|m| m.node_id())
+    }
+
+    pub fn node_ids(&self) -> &[String] {
+        self.membership
+            .as_ref()
+            .map(|m| m.node_ids())
+            .unwrap_or_default()
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &str> {
+        self.membership.iter().flat_map(|m| m.peers())
+    }
+
+    pub fn message(&self) -> Option<&Message> {
+        self.msg.as_ref()
+    }
+
     pub fn message_dest(&self) -> Option<&str> {
         self.msg
             .as_ref()
@@ -62,6 +164,18 @@ impl MessageContext {
         }
     }
 
+    /// Decodes the message into `T`, typically an enum deriving `MaelstromMessage`.
+    pub fn request<T>(&self) -> Result<T, ErrorMessage>
+    where
+        T: MaelstromMessage,
+    {
+        if let Some(msg) = self.msg.as_ref() {
+            T::from_content(&msg.body.content)
+        } else {
+            Err(ErrorMessage::new(ErrorKind::Crash, "message not available"))
+        }
+    }
+
     pub fn reply<T>(&self, kind: &str, data: &T) -> Result<(), ErrorMessage>
     where
         T: Serialize,
@@ -80,17 +194,184 @@ impl MessageContext {
         self.reply("error", error)
     }
 
+    pub fn respond(&self, response: Response) -> Result<(), ErrorMessage> {
+        let (kind, data) = response.into_parts();
+        self.push(&kind, data, self.message_src(), self.message_id());
+
+        Ok(())
+    }
+
+    /// Answers the message later, e.g. from the callback of an `rpc`, through the returned handle. The service
+    /// doesn't treat the missing reply as a handler bug then.
+    pub fn defer_reply(&self) -> Result<DeferredReply, ErrorMessage> {
+        let (Some(src), Some(msg_id)) = (self.message_src(), self.message_id()) else {
+            return Err(ErrorMessage::new(
+                ErrorKind::Crash,
+                "message doesn't expect a reply",
+            ));
+        };
+        let reply = self.deferred.defer(src, msg_id, self.outbox()?.clone());
+
+        self.reply_deferred.set(true);
+        Ok(reply)
+    }
+
+    /// Marks the message as answered by the service itself later on, e.g. `init` with a deferred init.
+    pub(crate) fn set_reply_deferred(&self) {
+        self.reply_deferred.set(true);
+    }
+
+    pub(crate) fn is_reply_deferred(&self) -> bool {
+        self.reply_deferred.get()
+    }
+
+    /// Sends a request to `dest` and calls `on_reply` with the context of its reply once it arrives.
+    pub fn rpc<T, F>(
+        &self,
+        dest: &str,
+        kind: &str,
+        data: &T,
+        on_reply: F,
+    ) -> Result<(), ErrorMessage>
+    where
+        T: Serialize,
+        F: FnOnce(&MessageContext) -> Result<(), ErrorMessage> + Send + 'static,
+    {
+        let msg_id = self.push(kind, serialize_message_content(data)?, Some(dest), None);
+        self.pending.register(msg_id, dest, Box::new(on_reply));
+
+        Ok(())
+    }
+
+    /// Sends `req` to `dest` and calls `on_reply` with its decoded response, or the error it was answered with.
+    pub fn call<R, F>(&self, dest: &str, req: &R, on_reply: F) -> Result<(), ErrorMessage>
+    where
+        R: Rpc,
+        F: FnOnce(&MessageContext, Result<R::Response, ErrorMessage>) -> Result<(), ErrorMessage>
+            + Send
+            + 'static,
+    {
+        self.rpc(dest, R::KIND, req, move |reply| {
+            on_reply(reply, decode_response::<R>(reply))
+        })
+    }
+
     pub fn broadcast<T>(&self, kind: &str, data: &T) -> Result<(), ErrorMessage>
     where
         T: Serialize,
     {
-        self.send(kind, data, None, None)
+        self.broadcast_with(
+            kind,
+            data,
+            BroadcastTargets::Cluster,
+            BroadcastOptions::default(),
+        )
+    }
+
+    pub fn broadcast_with<T>(
+        &self,
+        kind: &str,
+        data: &T,
+        targets: BroadcastTargets,
+        options: BroadcastOptions,
+    ) -> Result<(), ErrorMessage>
+    where
+        T: Serialize,
+    {
+        let membership = self.membership.as_ref().ok_or_else(|| {
+            ErrorMessage::new(ErrorKind::TemporarilyUnavailable, "node not initialized")
+        })?;
+
+        let nodes = match targets {
+            BroadcastTargets::Cluster => membership.node_ids(),
+            BroadcastTargets::Nodes(nodes) => nodes,
+        };
+
+        let data = serialize_message_content(data)?;
+        let sender = self.message_src();
+
+        for node in nodes {
+            if options.skip_self && node == membership.node_id() {
+                continue;
+            }
+            if options.skip_sender && Some(node.as_str()) == sender {
+                continue;
+            }
+
+            self.push(kind, data.clone(), Some(node), None);
+        }
+
+        Ok(())
+    }
+
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn outbox(&self) -> Result<&Outbox, ErrorMessage> {
+        self.outbox
+            .as_ref()
+            .ok_or_else(|| ErrorMessage::new(ErrorKind::Crash, "outbox not available"))
+    }
+
+    /// Holds back `init_ok` until the returned handle is completed, e.g. once a handler got a reply
+    /// from a service it has to contact during startup. Only available while handling `init`.
+    pub fn defer_init(&self) -> Result<DeferredInit, ErrorMessage> {
+        self.init
+            .as_ref()
+            .map(|init| init.defer())
+            .ok_or_else(|| ErrorMessage::new(ErrorKind::Crash, "not handling an init message"))
+    }
+
+    /// Counts the queued replies to the message being handled.
+    pub(crate) fn reply_count(&self) -> usize {
+        self.output
+            .borrow()
+            .iter()
+            .filter(|msg| self.is_reply(msg))
+            .count()
+    }
+
+    pub(crate) fn discard_replies(&self) {
+        let discarded = self.output.borrow_mut().drain(..).collect::<Vec<_>>();
+        let (replies, kept) = discarded.into_iter().partition(|msg| self.is_reply(msg));
+        *self.output.borrow_mut() = kept;
+        self.forget(replies);
+    }
+
+    pub(crate) fn output_len(&self) -> usize {
+        self.output.borrow().len()
+    }
+
+    /// Drops everything queued after `len` messages, e.g. the replies of a handler that failed later on.
+    pub(crate) fn truncate_output(&self, len: usize) {
+        let dropped = self.output.borrow_mut().split_off(len);
+        self.forget(dropped);
+    }
+
+    pub(crate) fn extend_output(&self, msgs: impl IntoIterator<Item = Message>) {
+        self.output.borrow_mut().extend(msgs);
     }
 
     pub fn into_output_iter(self) -> impl Iterator<Item = Message> {
         self.output.into_inner().into_iter()
     }
 
+    /// Unregisters the callbacks of the requests among messages that won't be sent after all.
+    fn forget(&self, dropped: impl IntoIterator<Item = Message>) {
+        for msg in dropped {
+            if let Some(msg_id) = msg.body.msg_id {
+                self.pending.take(msg_id);
+            }
+        }
+    }
+
+    fn is_reply(&self, msg: &Message) -> bool {
+        msg.body.in_reply_to.is_some()
+            && msg.body.in_reply_to == self.message_id()
+            && msg.dest.as_deref() == self.message_src()
+    }
+
     fn send<T>(
         &self,
         kind: &str,
@@ -101,15 +382,30 @@ impl MessageContext {
     where
         T: Serialize,
     {
+        self.push(kind, serialize_message_content(data)?, dest, in_reply_to);
+
+        Ok(())
+    }
+
+    fn push(
+        &self,
+        kind: &str,
+        data: DynamicMap,
+        dest: Option<&str>,
+        in_reply_to: Option<usize>,
+    ) -> usize {
+        let msg_id = self.msg_ids.next_id();
+        let src = self.message_dest().or(self.node_id());
+
         let msg = Message {
-            src: self.message_dest().map(|s| s.to_owned()),
+            src: src.map(|s| s.to_owned()),
             dest: dest.map(|s| s.to_owned()),
             body: MessageBody {
                 in_reply_to,
-                msg_id: Some(SHARED_MESSAGE_ID_COUNTER.fetch_add(1, Ordering::Relaxed)),
+                msg_id: Some(msg_id),
                 content: MessageContent {
                     kind: kind.to_string(),
-                    data: serialize_message_content(data)?,
+                    data,
                 },
             },
         };
@@ -117,6 +413,123 @@ impl MessageContext {
         let mut outgoing_msgs = self.output.borrow_mut();
         outgoing_msgs.push_back(msg);
 
-        Ok(())
+        msg_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    fn gossip_message(src: &str, dest: &str) -> Message {
+        Message {
+            src: Some(src.to_string()),
+            dest: Some(dest.to_string()),
+            body: MessageBody {
+                msg_id: Some(1),
+                in_reply_to: None,
+                content: MessageContent {
+                    kind: "gossip".to_string(),
+                    data: Default::default(),
+                },
+            },
+        }
+    }
+
+    fn membership() -> Arc<ClusterMembership> {
+        let node_ids = ["n1", "n2", "n3"].map(String::from);
+        Arc::new(ClusterMembership::new("n1", &node_ids))
+    }
+
+    #[test]
+    fn test_truncated_requests_are_forgotten() {
+        let pending = PendingRequests::default();
+        let ctx = MessageContext::new(Some(gossip_message("n2", "n1")))
+            .with_pending_requests(pending.clone());
+
+        ctx.rpc("lin-kv", "read", &(), |_| Ok(())).unwrap();
+        let checkpoint = ctx.output_len();
+        ctx.rpc("lin-kv", "write", &(), |_| Ok(())).unwrap();
+        assert_eq!(pending.len(), 2);
+
+        ctx.truncate_output(checkpoint);
+
+        assert_eq!(ctx.output_len(), 1);
+        assert_eq!(pending.len(), 1);
+    }
+
+    #[test]
+    fn test_membership_accessors() {
+        let ctx = MessageContext::new(Some(gossip_message("n2", "n1")));
+
+        assert!(!ctx.is_initialized());
+        assert_eq!(ctx.node_id(), None);
+        assert!(ctx.node_ids().is_empty());
+        assert_eq!(ctx.peers().count(), 0);
+
+        let ctx = ctx.with_membership(membership());
+
+        assert!(ctx.is_initialized());
+        assert_eq!(ctx.node_id(), Some("n1"));
+        assert_eq!(ctx.node_ids(), ["n1", "n2", "n3"]);
+        assert_eq!(ctx.peers().collect::<Vec<_>>(), vec!["n2", "n3"]);
+    }
+
+    #[test]
+    fn test_broadcast_cluster() {
+        let ctx =
+            MessageContext::new(Some(gossip_message("n2", "n1"))).with_membership(membership());
+
+        let mut data = DynamicMap::new();
+        data.insert("value".to_string(), Value::from(42));
+        ctx.broadcast("gossip", &data).unwrap();
+
+        let sent = ctx.into_output_iter().collect::<Vec<_>>();
+        let dests = sent
+            .iter()
+            .map(|msg| msg.dest.as_deref())
+            .collect::<Vec<_>>();
+
+        assert_eq!(dests, vec![Some("n2"), Some("n3")]);
+        assert!(sent.iter().all(|msg| msg.src.as_deref() == Some("n1")
+            && msg.body.in_reply_to.is_none()
+            && msg.body.content.data == data));
+    }
+
+    #[test]
+    fn test_broadcast_neighbours_skip_sender() {
+        let ctx =
+            MessageContext::new(Some(gossip_message("n2", "n1"))).with_membership(membership());
+
+        let neighbours = ["n1", "n2", "n3"].map(String::from);
+        ctx.broadcast_with(
+            "gossip",
+            &DynamicMap::new(),
+            BroadcastTargets::Nodes(&neighbours),
+            BroadcastOptions {
+                skip_self: true,
+                skip_sender: true,
+            },
+        )
+        .unwrap();
+
+        let dests = ctx
+            .into_output_iter()
+            .map(|msg| msg.dest)
+            .collect::<Vec<_>>();
+
+        assert_eq!(dests, vec![Some("n3".to_string())]);
+    }
+
+    #[test]
+    fn test_broadcast_before_init() {
+        let ctx = MessageContext::new(Some(gossip_message("n2", "n1")));
+
+        let res = ctx.broadcast("gossip", &DynamicMap::new());
+
+        assert!(res.is_err_and(|x| x.code() == usize::from(ErrorKind::TemporarilyUnavailable)));
+        assert_eq!(ctx.into_output_iter().count(), 0);
     }
 }
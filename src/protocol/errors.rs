@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use std::{error::Error, fmt::Display};
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "usize", into = "usize")]
 pub enum ErrorKind {
     Timeout, // Indicates that the requested operation could not be completed within a timeout.
     NodeNotFound, // Thrown when a client sends an RPC request to a node which does not exist.
@@ -13,6 +15,28 @@ pub enum ErrorKind {
     KeyAlreadyExists, // The client requested the creation of a key which already exists, and the server will not overwrite it.
     PreconditionFailed, // The requested operation expected some conditions to hold, and those conditions were not met. For instance, a compare-and-set operation might assert that the value of a key is currently 5; if the value is 3, the server would return precondition-failed.
     TxnConflict, // The requested transaction has been aborted because of a conflict with another transaction. Servers need not return this error on every conflict: they may choose to retry automatically instead.
+    Custom(CustomCode), // A code of 1000 or above, which Maelstrom leaves for user-defined errors.
+    Unknown(usize), // A code in Maelstrom's reserved range that doesn't belong to any of the kinds above.
+}
+
+/// The first code Maelstrom leaves for user-defined errors; everything below is reserved.
+pub const FIRST_CUSTOM_ERROR_CODE: usize = 1000;
+
+/// A user-defined error code, always at least `FIRST_CUSTOM_ERROR_CODE`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CustomCode(usize);
+
+impl CustomCode {
+    pub fn get(self) -> usize {
+        self.0
+    }
+}
+
+impl ErrorKind {
+    /// The kind for a user-defined error code, or `None` if `code` is in Maelstrom's reserved range.
+    pub fn custom(code: usize) -> Option<Self> {
+        (code >= FIRST_CUSTOM_ERROR_CODE).then_some(ErrorKind::Custom(CustomCode(code)))
+    }
 }
 
 impl From<ErrorKind> for usize {
@@ -29,6 +53,47 @@ impl From<ErrorKind> for usize {
             ErrorKind::KeyAlreadyExists => 21,
             ErrorKind::PreconditionFailed => 22,
             ErrorKind::TxnConflict => 30,
+            ErrorKind::Custom(code) => code.get(),
+            ErrorKind::Unknown(code) => code,
+        }
+    }
+}
+
+impl From<usize> for ErrorKind {
+    fn from(code: usize) -> Self {
+        match code {
+            0 => ErrorKind::Timeout,
+            1 => ErrorKind::NodeNotFound,
+            10 => ErrorKind::NotSupported,
+            11 => ErrorKind::TemporarilyUnavailable,
+            12 => ErrorKind::MalformedRequest,
+            13 => ErrorKind::Crash,
+            14 => ErrorKind::Abort,
+            20 => ErrorKind::KeyDoesNotExist,
+            21 => ErrorKind::KeyAlreadyExists,
+            22 => ErrorKind::PreconditionFailed,
+            30 => ErrorKind::TxnConflict,
+            code => ErrorKind::custom(code).unwrap_or(ErrorKind::Unknown(code)),
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::Timeout => write!(f, "timeout"),
+            ErrorKind::NodeNotFound => write!(f, "node-not-found"),
+            ErrorKind::NotSupported => write!(f, "not-supported"),
+            ErrorKind::TemporarilyUnavailable => write!(f, "temporarily-unavailable"),
+            ErrorKind::MalformedRequest => write!(f, "malformed-request"),
+            ErrorKind::Crash => write!(f, "crash"),
+            ErrorKind::Abort => write!(f, "abort"),
+            ErrorKind::KeyDoesNotExist => write!(f, "key-does-not-exist"),
+            ErrorKind::KeyAlreadyExists => write!(f, "key-already-exists"),
+            ErrorKind::PreconditionFailed => write!(f, "precondition-failed"),
+            ErrorKind::TxnConflict => write!(f, "txn-conflict"),
+            ErrorKind::Custom(code) => write!(f, "custom-{}", code.get()),
+            ErrorKind::Unknown(code) => write!(f, "unknown-{code}"),
         }
     }
 }
@@ -39,7 +104,7 @@ pub struct ErrorMessage {
     code: usize,
     text: String,
     #[serde(skip_serializing, skip_deserializing)]
-    source: Option<Box<dyn Error + 'static>>,
+    source: Option<Box<dyn Error + Send + Sync + 'static>>,
 }
 
 impl ErrorMessage {
@@ -51,17 +116,33 @@ impl ErrorMessage {
         }
     }
 
-    pub fn with_source(self, source: impl Error + 'static) -> ErrorMessage {
+    pub fn with_source(self, source: impl Error + Send + Sync + 'static) -> ErrorMessage {
         ErrorMessage {
             source: Some(Box::new(source)),
             ..self
         }
     }
 
+    /// Merges several errors into one that keeps the code of the first and lists all of their texts.
+    pub fn combine(errors: impl IntoIterator<Item = ErrorMessage>) -> Option<ErrorMessage> {
+        let mut errors = errors.into_iter();
+        let first = errors.next()?;
+
+        let text = errors.fold(first.text.clone(), |text, err| {
+            format!("{}; {}", text, err.text)
+        });
+
+        Some(ErrorMessage { text, ..first })
+    }
+
     pub fn code(&self) -> usize {
         self.code
     }
 
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::from(self.code)
+    }
+
     pub fn text(&self) -> &str {
         &self.text
     }
@@ -80,7 +161,9 @@ impl Display for ErrorMessage {
 
 impl Error for ErrorMessage {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        self.source.as_ref().map(|e| e.as_ref())
+        self.source
+            .as_ref()
+            .map(|e| e.as_ref() as &(dyn Error + 'static))
     }
 }
 
@@ -90,6 +173,21 @@ mod tests {
     use std::io::{Error, ErrorKind as IOErrorKind};
 
     #[test]
+    fn test_combine() {
+        assert!(ErrorMessage::combine([]).is_none());
+
+        let err = ErrorMessage::combine([
+            ErrorMessage::new(ErrorKind::Abort, "first"),
+            ErrorMessage::new(ErrorKind::Crash, "second"),
+        ])
+        .unwrap();
+
+        assert_eq!(err.code(), usize::from(ErrorKind::Abort));
+        assert_eq!(err.text(), "first; second");
+    }
+
+    #[test]
+    #[allow(clippy::io_other_error)]
     fn test_display() {
         let err = ErrorMessage::new(ErrorKind::Crash, "something went wrong");
         assert_eq!("[13] something went wrong", format!("{}", err));
@@ -101,4 +199,64 @@ mod tests {
             format!("{}", err)
         );
     }
+
+    #[test]
+    fn test_kind_round_trip() {
+        let kinds = [
+            ErrorKind::Timeout,
+            ErrorKind::NodeNotFound,
+            ErrorKind::NotSupported,
+            ErrorKind::TemporarilyUnavailable,
+            ErrorKind::MalformedRequest,
+            ErrorKind::Crash,
+            ErrorKind::Abort,
+            ErrorKind::KeyDoesNotExist,
+            ErrorKind::KeyAlreadyExists,
+            ErrorKind::PreconditionFailed,
+            ErrorKind::TxnConflict,
+            ErrorKind::custom(1000).unwrap(),
+            ErrorKind::custom(4242).unwrap(),
+            ErrorKind::Unknown(15),
+        ];
+
+        for kind in kinds {
+            assert_eq!(ErrorKind::from(usize::from(kind)), kind);
+
+            let json = serde_json::to_string(&kind).unwrap();
+            assert_eq!(json, usize::from(kind).to_string());
+            assert_eq!(serde_json::from_str::<ErrorKind>(&json).unwrap(), kind);
+        }
+
+        assert_eq!(ErrorKind::custom(999), None);
+        assert_eq!(
+            serde_json::from_str::<ErrorKind>("999").unwrap(),
+            ErrorKind::Unknown(999)
+        );
+    }
+
+    #[test]
+    fn test_kind_display() {
+        assert_eq!(
+            ErrorKind::TemporarilyUnavailable.to_string(),
+            "temporarily-unavailable"
+        );
+        assert_eq!(ErrorKind::custom(1001).unwrap().to_string(), "custom-1001");
+        assert_eq!(ErrorKind::Unknown(15).to_string(), "unknown-15");
+    }
+
+    #[test]
+    fn test_message_kind() {
+        let err =
+            serde_json::from_str::<ErrorMessage>(r#"{"code":22,"text":"cas failed"}"#).unwrap();
+        assert_eq!(err.kind(), ErrorKind::PreconditionFailed);
+        assert_eq!(err.code(), 22);
+
+        let kind = ErrorKind::custom(1234).unwrap();
+        let err = ErrorMessage::new(kind, "retry later");
+        assert_eq!(err.kind(), kind);
+
+        let err = serde_json::from_str::<ErrorMessage>(r#"{"code":42,"text":"new"}"#).unwrap();
+        assert_eq!(err.kind(), ErrorKind::Unknown(42));
+        assert_eq!(err.code(), 42);
+    }
 }
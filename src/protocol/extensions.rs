@@ -0,0 +1,172 @@
+use std::{
+    any::{type_name, Any, TypeId},
+    collections::HashMap,
+    sync::{Arc, RwLock, TryLockError},
+};
+
+use super::{ErrorKind, ErrorMessage};
+
+type Extension = Arc<RwLock<Box<dyn Any + Send + Sync>>>;
+
+/// Typed values shared by every handler of a node, at most one per type. Clones share the same values.
+///
+/// [`Extensions::with`] gives shared access and [`Extensions::with_mut`] exclusive access to a value.
+/// Accessing a value from within a closure that already holds it, e.g. calling `with_mut::<T>` inside
+/// `with::<T>`, fails with a `Crash` error instead of blocking, as does accessing it while a worker thread
+/// holds it exclusively; nesting access to values of different types is fine.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    values: Arc<RwLock<HashMap<TypeId, Extension>>>,
+}
+
+impl Extensions {
+    pub fn insert<T>(&self, value: T)
+    where
+        T: Send + Sync + 'static,
+    {
+        if let Ok(mut values) = self.values.write() {
+            values.insert(TypeId::of::<T>(), Arc::new(RwLock::new(Box::new(value))));
+        }
+    }
+
+    pub fn contains<T>(&self) -> bool
+    where
+        T: Send + Sync + 'static,
+    {
+        self.values
+            .read()
+            .is_ok_and(|values| values.contains_key(&TypeId::of::<T>()))
+    }
+
+    pub fn remove<T>(&self) -> bool
+    where
+        T: Send + Sync + 'static,
+    {
+        self.values
+            .write()
+            .is_ok_and(|mut values| values.remove(&TypeId::of::<T>()).is_some())
+    }
+
+    pub fn with<T, R>(&self, f: impl FnOnce(&T) -> R) -> Result<R, ErrorMessage>
+    where
+        T: Send + Sync + 'static,
+    {
+        let extension = self.extension::<T>()?;
+        let value = extension.try_read().map_err(borrow_error::<T, _>)?;
+
+        value
+            .downcast_ref::<T>()
+            .map(f)
+            .ok_or_else(|| missing::<T>())
+    }
+
+    pub fn with_mut<T, R>(&self, f: impl FnOnce(&mut T) -> R) -> Result<R, ErrorMessage>
+    where
+        T: Send + Sync + 'static,
+    {
+        let extension = self.extension::<T>()?;
+        let mut value = extension.try_write().map_err(borrow_error::<T, _>)?;
+
+        value
+            .downcast_mut::<T>()
+            .map(f)
+            .ok_or_else(|| missing::<T>())
+    }
+
+    fn extension<T>(&self) -> Result<Extension, ErrorMessage>
+    where
+        T: 'static,
+    {
+        // The map lock is released before the value is locked, so handlers can nest access to different types
+        let values = self.values.read().map_err(|_| poisoned::<T>())?;
+        values
+            .get(&TypeId::of::<T>())
+            .cloned()
+            .ok_or_else(|| missing::<T>())
+    }
+}
+
+fn missing<T>() -> ErrorMessage {
+    ErrorMessage::new(
+        ErrorKind::Crash,
+        &format!("extension `{}` not registered", type_name::<T>()),
+    )
+}
+
+fn borrow_error<T, G>(error: TryLockError<G>) -> ErrorMessage {
+    match error {
+        TryLockError::Poisoned(_) => poisoned::<T>(),
+        TryLockError::WouldBlock => ErrorMessage::new(
+            ErrorKind::Crash,
+            &format!("extension `{}` already borrowed", type_name::<T>()),
+        ),
+    }
+}
+
+fn poisoned<T>() -> ErrorMessage {
+    ErrorMessage::new(
+        ErrorKind::Crash,
+        &format!("extension `{}` poisoned", type_name::<T>()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Topology(Vec<String>);
+
+    #[test]
+    fn test_shared_access() {
+        let extensions = Extensions::default();
+        let other = extensions.clone();
+
+        extensions.insert(Topology(vec!["n2".to_string()]));
+        other
+            .with_mut(|topology: &mut Topology| topology.0.push("n3".to_string()))
+            .unwrap();
+
+        let len = extensions
+            .with(|topology: &Topology| topology.0.len())
+            .unwrap();
+        assert_eq!(len, 2);
+
+        // Nested access to values of different types
+        extensions.insert(42usize);
+        let sum = extensions
+            .with(|topology: &Topology| extensions.with(|n: &usize| topology.0.len() + n))
+            .unwrap()
+            .unwrap();
+        assert_eq!(sum, 44);
+    }
+
+    #[test]
+    fn test_already_borrowed() {
+        let extensions = Extensions::default();
+        extensions.insert(42usize);
+
+        let res = extensions
+            .with(|_: &usize| extensions.with_mut(|n: &mut usize| *n += 1))
+            .unwrap();
+        assert!(res.is_err_and(|x| x.text() == "extension `usize` already borrowed"));
+
+        let res = extensions
+            .with_mut(|_: &mut usize| extensions.with(|n: &usize| *n))
+            .unwrap();
+        assert!(res.is_err_and(|x| x.code() == usize::from(ErrorKind::Crash)));
+    }
+
+    #[test]
+    fn test_missing() {
+        let extensions = Extensions::default();
+        extensions.insert(Topology(Vec::new()));
+
+        assert!(extensions.contains::<Topology>());
+        assert!(extensions.remove::<Topology>());
+        assert!(!extensions.contains::<Topology>());
+
+        let res = extensions.with(|topology: &Topology| topology.0.len());
+        assert!(res.is_err_and(|x| x.code() == usize::from(ErrorKind::Crash)));
+    }
+}
@@ -1,11 +1,9 @@
-use super::{ErrorMessage, MessageContext};
+use super::{ErrorMessage, MessageContext, Response};
 
+/// Handles the message kinds it lists. Handlers registered by type are created with `Default`, the others are
+/// registered as instances, e.g. when they need configuration.
 pub trait MessageHandler {
-    fn new() -> Self
-    where
-        Self: Sized;
-
-    fn get_handled_messages() -> impl Iterator<Item = &'static str>
+    fn get_handled_messages(&self) -> impl Iterator<Item = &str>
     where
         Self: Sized;
 
@@ -18,5 +16,31 @@ pub trait MessageHandler {
         Ok(())
     }
 
-    fn handle(&mut self, ctx: &MessageContext) -> Result<(), ErrorMessage>;
+    /// Called before the node is initialized again from scratch, see `ReinitPolicy::Reset`.
+    fn reset(&mut self) {}
+
+    /// Called periodically once the node is initialized, see `MaelstromService::set_tick_interval`.
+    fn on_tick(&mut self, _ctx: &MessageContext) -> Result<(), ErrorMessage> {
+        Ok(())
+    }
+
+    /// Called once `peer` hasn't sent anything for longer than `MaelstromService::set_peer_timeout`, and again
+    /// only after it has been heard from in the meantime.
+    fn on_peer_unreachable(
+        &mut self,
+        _peer: &str,
+        _ctx: &MessageContext,
+    ) -> Result<(), ErrorMessage> {
+        Ok(())
+    }
+
+    /// Called when stdin closes. Messages sent from here are still written out before the node exits.
+    fn on_shutdown(&mut self, _ctx: &MessageContext) -> Result<(), ErrorMessage> {
+        Ok(())
+    }
+
+    /// Handles a message that was routed to this handler. A returned response is sent in reply to it; handlers
+    /// that answer later, e.g. after an `rpc` of their own, return `None` and answer through the handle returned
+    /// by `MessageContext::defer_reply`.
+    fn handle(&mut self, ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage>;
 }
@@ -0,0 +1,119 @@
+use std::sync::{Arc, Mutex};
+
+use super::{ErrorKind, ErrorMessage};
+
+pub enum InitStatus {
+    Ready,
+    Pending,
+    Failed(ErrorMessage),
+}
+
+#[derive(Default)]
+struct InitState {
+    outstanding: usize,
+    error: Option<ErrorMessage>,
+}
+
+/// Keeps track of the handlers that deferred their initialization while an `init` message is being handled.
+#[derive(Clone, Default)]
+pub struct InitTracker {
+    state: Arc<Mutex<InitState>>,
+}
+
+impl InitTracker {
+    pub fn defer(&self) -> DeferredInit {
+        if let Ok(mut state) = self.state.lock() {
+            state.outstanding += 1;
+        }
+
+        DeferredInit {
+            tracker: self.clone(),
+            resolved: false,
+        }
+    }
+
+    pub fn status(&self) -> InitStatus {
+        match self.state.lock() {
+            Ok(mut state) => match state.error.take() {
+                Some(error) => InitStatus::Failed(error),
+                None if state.outstanding > 0 => InitStatus::Pending,
+                None => InitStatus::Ready,
+            },
+            Err(_) => {
+                InitStatus::Failed(ErrorMessage::new(ErrorKind::Crash, "init tracker poisoned"))
+            }
+        }
+    }
+
+    fn resolve(&self, error: Option<ErrorMessage>) {
+        if let Ok(mut state) = self.state.lock() {
+            state.outstanding = state.outstanding.saturating_sub(1);
+            if state.error.is_none() {
+                state.error = error;
+            }
+        }
+    }
+}
+
+/// Returned by [`super::MessageContext::defer_init`]. The node replies with `init_ok` only once every
+/// deferred handler has called [`DeferredInit::complete`]; dropping it unresolved fails the `init`.
+pub struct DeferredInit {
+    tracker: InitTracker,
+    resolved: bool,
+}
+
+impl DeferredInit {
+    pub fn complete(mut self) {
+        self.resolved = true;
+        self.tracker.resolve(None);
+    }
+
+    pub fn fail(mut self, error: ErrorMessage) {
+        self.resolved = true;
+        self.tracker.resolve(Some(error));
+    }
+}
+
+impl Drop for DeferredInit {
+    fn drop(&mut self) {
+        if !self.resolved {
+            self.tracker.resolve(Some(ErrorMessage::new(
+                ErrorKind::Crash,
+                "deferred init dropped before completion",
+            )));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deferred_init() {
+        let tracker = InitTracker::default();
+        assert!(matches!(tracker.status(), InitStatus::Ready));
+
+        let first = tracker.defer();
+        let second = tracker.defer();
+        assert!(matches!(tracker.status(), InitStatus::Pending));
+
+        first.complete();
+        assert!(matches!(tracker.status(), InitStatus::Pending));
+
+        second.complete();
+        assert!(matches!(tracker.status(), InitStatus::Ready));
+    }
+
+    #[test]
+    fn test_deferred_init_dropped() {
+        let tracker = InitTracker::default();
+
+        drop(tracker.defer());
+
+        assert!(matches!(
+            tracker.status(),
+            InitStatus::Failed(err) if err.code() == usize::from(ErrorKind::Crash)
+        ));
+    }
+}
@@ -0,0 +1,29 @@
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClusterMembership {
+    node_id: String,
+    node_ids: Vec<String>,
+}
+
+impl ClusterMembership {
+    pub fn new(node_id: &str, node_ids: &[String]) -> Self {
+        Self {
+            node_id: node_id.to_owned(),
+            node_ids: node_ids.to_vec(),
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn node_ids(&self) -> &[String] {
+        &self.node_ids
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &str> {
+        self.node_ids
+            .iter()
+            .map(|s| s.as_str())
+            .filter(move |id| *id != self.node_id)
+    }
+}
@@ -0,0 +1,54 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Hands out `msg_id`s for outgoing messages. Clones share the same counter, so a node can pass
+/// the allocator to every context and outbox it creates without producing duplicate ids.
+#[derive(Clone, Debug)]
+pub struct MessageIdAllocator {
+    next: Arc<AtomicUsize>,
+    start: usize,
+}
+
+impl MessageIdAllocator {
+    pub fn new(start: usize) -> Self {
+        Self {
+            next: Arc::new(AtomicUsize::new(start)),
+            start,
+        }
+    }
+
+    pub fn next_id(&self) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.next.store(self.start, Ordering::Relaxed);
+    }
+}
+
+impl Default for MessageIdAllocator {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_counter() {
+        let msg_ids = MessageIdAllocator::new(100);
+        let other = msg_ids.clone();
+
+        assert_eq!(msg_ids.next_id(), 100);
+        assert_eq!(other.next_id(), 101);
+        assert_eq!(MessageIdAllocator::new(100).next_id(), 100);
+
+        other.reset();
+
+        assert_eq!(msg_ids.next_id(), 100);
+    }
+}
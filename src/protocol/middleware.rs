@@ -0,0 +1,24 @@
+use super::{ErrorMessage, Message, MessageContext};
+
+/// Runs around the handlers of every incoming message. Middlewares are called in registration order
+/// before the handlers, and in reverse order after them.
+pub trait Middleware {
+    /// Returning an error skips the handlers and the remaining middlewares, and is sent back as the reply.
+    fn before(&mut self, _ctx: &MessageContext) -> Result<(), ErrorMessage> {
+        Ok(())
+    }
+
+    /// Receives the result of the handlers (or of a short-circuiting middleware) and may replace it.
+    fn after(
+        &mut self,
+        _ctx: &MessageContext,
+        result: Result<(), ErrorMessage>,
+    ) -> Result<(), ErrorMessage> {
+        result
+    }
+
+    /// Called for every message sent while handling the incoming one; returning `None` drops it.
+    fn outgoing(&mut self, msg: Message) -> Option<Message> {
+        Some(msg)
+    }
+}
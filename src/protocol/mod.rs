@@ -1,10 +1,30 @@
 mod context;
 mod errors;
+mod extensions;
 mod handler;
+mod init;
+mod membership;
+mod message_id;
+mod middleware;
+mod outbox;
 mod payload;
+mod reply;
+mod response;
+mod rpc;
 mod serialization;
+mod typed;
 
 pub use context::*;
 pub use errors::*;
+pub use extensions::*;
 pub use handler::*;
+pub use init::*;
+pub use membership::*;
+pub use message_id::*;
+pub use middleware::*;
+pub use outbox::*;
 pub use payload::*;
+pub use reply::*;
+pub use response::*;
+pub use rpc::*;
+pub use typed::*;
@@ -0,0 +1,324 @@
+use std::sync::{
+    mpsc::{self, Receiver, Sender},
+    Arc, Mutex, RwLock,
+};
+
+use serde::Serialize;
+
+use super::{
+    decode_response, serialization::serialize_message_content, ErrorKind, ErrorMessage, Message,
+    MessageBody, MessageContent, MessageContext, MessageIdAllocator, PendingRequests, Rpc,
+};
+
+/// Rewrites or drops the messages built by the outbox, see `MaelstromService::register_middleware`.
+type OutgoingFilter = Arc<dyn Fn(Message) -> Option<Message> + Send + Sync>;
+
+enum Stage {
+    Holding(Vec<Message>), // Messages are kept until the stage is released or discarded.
+    Released,              // Messages go straight to the channel.
+    Discarded,             // Messages are refused.
+}
+
+#[derive(Clone)]
+pub struct Outbox {
+    tx: Sender<Message>,
+    node_id: Arc<RwLock<Option<String>>>,
+    msg_ids: MessageIdAllocator,
+    pending: PendingRequests,
+    stage: Option<Arc<Mutex<Stage>>>,
+    outgoing: Arc<RwLock<Option<OutgoingFilter>>>,
+}
+
+impl Outbox {
+    pub fn new(msg_ids: MessageIdAllocator, pending: PendingRequests) -> (Self, Receiver<Message>) {
+        let (tx, rx) = mpsc::channel();
+        let outbox = Self {
+            tx,
+            node_id: Default::default(),
+            msg_ids,
+            pending,
+            stage: None,
+            outgoing: Default::default(),
+        };
+
+        (outbox, rx)
+    }
+
+    /// An outbox that sends as `node_id` but holds its messages back until it's released, or drops them
+    /// if it's discarded. Copies of it that handlers kept send straight to this outbox once it's released.
+    pub(crate) fn staged(&self, node_id: &str) -> Self {
+        Self {
+            node_id: Arc::new(RwLock::new(Some(node_id.to_owned()))),
+            stage: Some(Arc::new(Mutex::new(Stage::Holding(Vec::new())))),
+            ..self.clone()
+        }
+    }
+
+    /// Sends the messages held back by a staged outbox.
+    pub(crate) fn release(&self) -> Result<(), ErrorMessage> {
+        for msg in self.finish_stage(Stage::Released) {
+            self.tx.send(msg).map_err(|err| {
+                ErrorMessage::new(ErrorKind::Crash, "outbox closed").with_source(err)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Drops the messages held back by a staged outbox, along with the callbacks of the requests among them.
+    pub(crate) fn discard(&self) {
+        for msg in self.finish_stage(Stage::Discarded) {
+            if let Some(msg_id) = msg.body.msg_id {
+                self.pending.take(msg_id);
+            }
+        }
+    }
+
+    fn finish_stage(&self, next: Stage) -> Vec<Message> {
+        let Some(Ok(mut stage)) = self.stage.as_ref().map(|stage| stage.lock()) else {
+            return Vec::new();
+        };
+        match std::mem::replace(&mut *stage, next) {
+            Stage::Holding(held) => held,
+            Stage::Released | Stage::Discarded => Vec::new(),
+        }
+    }
+
+    pub fn send<T>(&self, dest: &str, kind: &str, data: &T) -> Result<(), ErrorMessage>
+    where
+        T: Serialize,
+    {
+        self.post_outgoing(self.message(self.msg_ids.next_id(), dest, None, kind, data)?)
+    }
+
+    /// Sends a message to `dest` in reply to its message `in_reply_to`, e.g. to answer a request later on.
+    pub fn send_reply<T>(
+        &self,
+        dest: &str,
+        in_reply_to: usize,
+        kind: &str,
+        data: &T,
+    ) -> Result<(), ErrorMessage>
+    where
+        T: Serialize,
+    {
+        self.post_outgoing(self.reply_message(dest, in_reply_to, kind, data)?)
+    }
+
+    pub(crate) fn reply_message<T>(
+        &self,
+        dest: &str,
+        in_reply_to: usize,
+        kind: &str,
+        data: &T,
+    ) -> Result<Message, ErrorMessage>
+    where
+        T: Serialize,
+    {
+        self.message(self.msg_ids.next_id(), dest, Some(in_reply_to), kind, data)
+    }
+
+    /// Sends a request to `dest` and calls `on_reply` with the context of its reply once it arrives.
+    pub fn rpc<T, F>(
+        &self,
+        dest: &str,
+        kind: &str,
+        data: &T,
+        on_reply: F,
+    ) -> Result<(), ErrorMessage>
+    where
+        T: Serialize,
+        F: FnOnce(&MessageContext) -> Result<(), ErrorMessage> + Send + 'static,
+    {
+        // Registered before sending, so the reply cannot arrive before its callback
+        let msg_id = self.msg_ids.next_id();
+        self.pending.register(msg_id, dest, Box::new(on_reply));
+
+        let sent = self.post_outgoing(self.message(msg_id, dest, None, kind, data)?);
+        if sent.is_err() {
+            self.pending.take(msg_id);
+        }
+        sent
+    }
+
+    /// Typed counterpart of `rpc`, see `MessageContext::call`.
+    pub fn call<R, F>(&self, dest: &str, req: &R, on_reply: F) -> Result<(), ErrorMessage>
+    where
+        R: Rpc,
+        F: FnOnce(&MessageContext, Result<R::Response, ErrorMessage>) -> Result<(), ErrorMessage>
+            + Send
+            + 'static,
+    {
+        self.rpc(dest, R::KIND, req, move |reply| {
+            on_reply(reply, decode_response::<R>(reply))
+        })
+    }
+
+    fn message<T>(
+        &self,
+        msg_id: usize,
+        dest: &str,
+        in_reply_to: Option<usize>,
+        kind: &str,
+        data: &T,
+    ) -> Result<Message, ErrorMessage>
+    where
+        T: Serialize,
+    {
+        let src = self.node_id.read().ok().and_then(|node_id| node_id.clone());
+
+        Ok(Message {
+            src,
+            dest: Some(dest.to_owned()),
+            body: MessageBody {
+                msg_id: Some(msg_id),
+                in_reply_to,
+                content: MessageContent {
+                    kind: kind.to_string(),
+                    data: serialize_message_content(data)?,
+                },
+            },
+        })
+    }
+
+    /// Passes a message built by the outbox through the outgoing filter, or gives it back as is if there's none.
+    pub(crate) fn outgoing(&self, msg: Message) -> Option<Message> {
+        // Cloned out of the lock, since dropping a request may send the error of a deferred reply through here
+        let filter = self.outgoing.read().ok().and_then(|filter| filter.clone());
+        match filter {
+            Some(filter) => filter(msg),
+            None => Some(msg),
+        }
+    }
+
+    pub(crate) fn set_outgoing(
+        &self,
+        filter: impl Fn(Message) -> Option<Message> + Send + Sync + 'static,
+    ) {
+        if let Ok(mut outgoing) = self.outgoing.write() {
+            *outgoing = Some(Arc::new(filter));
+        }
+    }
+
+    fn post_outgoing(&self, msg: Message) -> Result<(), ErrorMessage> {
+        match self.outgoing(msg) {
+            Some(msg) => self.post(msg),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends `msg` as is. Unlike the messages built by `send`, `send_reply` and `rpc`, it doesn't go through the
+    /// middlewares, since the output of `MaelstromService::input` and `tick` posted here already has.
+    pub fn post(&self, msg: Message) -> Result<(), ErrorMessage> {
+        if let Some(Ok(mut stage)) = self.stage.as_ref().map(|stage| stage.lock()) {
+            match &mut *stage {
+                Stage::Holding(held) => {
+                    held.push(msg);
+                    return Ok(());
+                }
+                Stage::Discarded => {
+                    return Err(ErrorMessage::new(ErrorKind::Crash, "outbox discarded"))
+                }
+                Stage::Released => {}
+            }
+        }
+
+        self.tx
+            .send(msg)
+            .map_err(|err| ErrorMessage::new(ErrorKind::Crash, "outbox closed").with_source(err))
+    }
+
+    pub(crate) fn bind(&self, node_id: &str) {
+        if let Ok(mut bound) = self.node_id.write() {
+            *bound = Some(node_id.to_owned());
+        }
+    }
+
+    pub(crate) fn unbind(&self) {
+        if let Ok(mut bound) = self.node_id.write() {
+            *bound = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::protocol::DynamicMap;
+
+    #[test]
+    fn test_send() {
+        let (outbox, rx) = Outbox::new(Default::default(), Default::default());
+        outbox.bind("n1");
+
+        let mut data = DynamicMap::new();
+        data.insert("value".to_string(), Value::from(42));
+
+        let background = outbox.clone();
+        std::thread::spawn(move || background.send("n2", "gossip", &data))
+            .join()
+            .unwrap()
+            .unwrap();
+
+        let msg = rx.try_recv().unwrap();
+
+        assert_eq!(msg.src, Some("n1".to_string()));
+        assert_eq!(msg.dest, Some("n2".to_string()));
+        assert_eq!(msg.kind(), "gossip");
+        assert_eq!(msg.body.in_reply_to, None);
+        assert_eq!(msg.body.content.data.get("value"), Some(&Value::from(42)));
+    }
+
+    #[test]
+    fn test_staged() {
+        let pending = PendingRequests::default();
+        let (outbox, rx) = Outbox::new(Default::default(), pending.clone());
+
+        let staged = outbox.staged("n1");
+        staged.send("n2", "gossip", &()).unwrap();
+        assert!(rx.try_recv().is_err());
+
+        staged.release().unwrap();
+        assert_eq!(rx.try_recv().unwrap().src, Some("n1".to_string()));
+
+        staged.send("n2", "gossip", &()).unwrap();
+        assert!(rx.try_recv().is_ok());
+
+        let staged = outbox.staged("n1");
+        staged.rpc("lin-kv", "read", &(), |_| Ok(())).unwrap();
+        assert_eq!(pending.len(), 1);
+
+        staged.discard();
+        assert!(rx.try_recv().is_err());
+        assert!(pending.is_empty());
+        assert!(staged.send("n2", "gossip", &()).is_err());
+    }
+
+    #[test]
+    fn test_outgoing() {
+        let pending = PendingRequests::default();
+        let (outbox, rx) = Outbox::new(Default::default(), pending.clone());
+        outbox.set_outgoing(|msg| (msg.dest.as_deref() != Some("lin-kv")).then_some(msg));
+
+        outbox.send("n2", "gossip", &()).unwrap();
+        outbox.rpc("lin-kv", "read", &(), |_| Ok(())).unwrap();
+        assert_eq!(rx.try_recv().unwrap().dest, Some("n2".to_string()));
+        assert!(rx.try_recv().is_err());
+
+        // Posted messages are sent as is
+        let msg = outbox.message(1, "lin-kv", None, "read", &()).unwrap();
+        outbox.post(msg).unwrap();
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_send_closed() {
+        let (outbox, rx) = Outbox::new(Default::default(), Default::default());
+        drop(rx);
+
+        let res = outbox.send("n2", "gossip", &DynamicMap::new());
+
+        assert!(res.is_err_and(|x| x.code() == usize::from(ErrorKind::Crash)));
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use serde::Serialize;
+
+use super::{ErrorKind, ErrorMessage, Message, Outbox, Response};
+
+/// Called with the sender and `msg_id` of every request whose deferred reply is resolved, along with the reply
+/// it got, or `None` if it was dropped unanswered.
+type ResolveHook = Box<dyn Fn(&str, usize, Option<&Message>) + Send + Sync>;
+
+/// Keeps track of the requests whose reply was deferred with [`super::MessageContext::defer_reply`] and hasn't
+/// been sent yet, keyed by their sender and `msg_id`.
+#[derive(Clone, Default)]
+pub struct DeferredReplies {
+    outstanding: Arc<Mutex<HashSet<(String, usize)>>>,
+    on_resolve: Arc<RwLock<Option<ResolveHook>>>,
+}
+
+impl DeferredReplies {
+    pub fn defer(&self, dest: &str, msg_id: usize, outbox: Outbox) -> DeferredReply {
+        if let Ok(mut outstanding) = self.outstanding.lock() {
+            outstanding.insert((dest.to_owned(), msg_id));
+        }
+
+        DeferredReply {
+            tracker: self.clone(),
+            dest: dest.to_owned(),
+            msg_id,
+            outbox,
+            answered: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.outstanding
+            .lock()
+            .map(|outstanding| outstanding.len())
+            .unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Lets the service see the replies as they're sent, e.g. to remember them for deduplication.
+    pub(crate) fn set_on_resolve(
+        &self,
+        hook: impl Fn(&str, usize, Option<&Message>) + Send + Sync + 'static,
+    ) {
+        if let Ok(mut on_resolve) = self.on_resolve.write() {
+            *on_resolve = Some(Box::new(hook));
+        }
+    }
+
+    fn resolve(&self, dest: &str, msg_id: usize, reply: Option<&Message>) {
+        if let Ok(mut outstanding) = self.outstanding.lock() {
+            outstanding.remove(&(dest.to_owned(), msg_id));
+        }
+        if let Ok(on_resolve) = self.on_resolve.read() {
+            if let Some(hook) = &*on_resolve {
+                hook(dest, msg_id, reply);
+            }
+        }
+    }
+}
+
+/// Returned by [`super::MessageContext::defer_reply`] to answer the request later, e.g. from the callback of
+/// an `rpc`. The answer goes out through the outbox in reply to the request; dropping the handle unanswered
+/// replies with a `Crash` error, so the client isn't left waiting.
+pub struct DeferredReply {
+    tracker: DeferredReplies,
+    dest: String,
+    msg_id: usize,
+    outbox: Outbox,
+    answered: bool,
+}
+
+impl DeferredReply {
+    pub fn dest(&self) -> &str {
+        &self.dest
+    }
+
+    pub fn msg_id(&self) -> usize {
+        self.msg_id
+    }
+
+    pub fn reply<T>(mut self, kind: &str, data: &T) -> Result<(), ErrorMessage>
+    where
+        T: Serialize,
+    {
+        self.answered = true;
+        let reply = self
+            .outbox
+            .reply_message(&self.dest, self.msg_id, kind, data)
+            .map(|reply| self.outbox.outgoing(reply));
+        let sent = reply.as_ref().ok().and_then(Option::as_ref);
+        self.tracker.resolve(&self.dest, self.msg_id, sent);
+        match reply? {
+            Some(reply) => self.outbox.post(reply),
+            None => Ok(()),
+        }
+    }
+
+    pub fn respond(self, response: Response) -> Result<(), ErrorMessage> {
+        let (kind, data) = response.into_parts();
+        self.reply(&kind, &data)
+    }
+
+    pub fn error(self, error: &ErrorMessage) -> Result<(), ErrorMessage> {
+        self.reply("error", error)
+    }
+}
+
+impl Drop for DeferredReply {
+    fn drop(&mut self) {
+        if !self.answered {
+            self.tracker.resolve(&self.dest, self.msg_id, None);
+            let error =
+                ErrorMessage::new(ErrorKind::Crash, "request dropped before it was answered");
+            let _ = self
+                .outbox
+                .send_reply(&self.dest, self.msg_id, "error", &error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deferred_reply() {
+        let (outbox, rx) = Outbox::new(Default::default(), Default::default());
+        let replies = DeferredReplies::default();
+
+        let reply = replies.defer("c1", 7, outbox.clone());
+        assert_eq!(replies.len(), 1);
+
+        reply.reply("read_ok", &()).unwrap();
+        assert!(replies.is_empty());
+
+        let msg = rx.try_recv().unwrap();
+        assert_eq!(msg.kind(), "read_ok");
+        assert_eq!(msg.dest, Some("c1".to_string()));
+        assert_eq!(msg.body.in_reply_to, Some(7));
+    }
+
+    #[test]
+    fn test_deferred_reply_dropped() {
+        let (outbox, rx) = Outbox::new(Default::default(), Default::default());
+        let replies = DeferredReplies::default();
+
+        drop(replies.defer("c1", 7, outbox));
+
+        assert!(replies.is_empty());
+        let msg = rx.try_recv().unwrap();
+        assert_eq!(msg.kind(), "error");
+        assert_eq!(msg.body.in_reply_to, Some(7));
+    }
+}
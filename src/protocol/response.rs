@@ -0,0 +1,42 @@
+use serde::Serialize;
+
+use super::{serialization::serialize_message_content, DynamicMap, ErrorMessage, Rpc};
+
+/// A reply returned from `MessageHandler::handle`, which the service sends in reply to the handled message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    kind: String,
+    data: DynamicMap,
+}
+
+impl Response {
+    pub fn new<T>(kind: &str, data: &T) -> Result<Self, ErrorMessage>
+    where
+        T: Serialize,
+    {
+        Ok(Self {
+            kind: kind.to_owned(),
+            data: serialize_message_content(data)?,
+        })
+    }
+
+    /// The response to the request `R`, sent as `R::RESPONSE_KIND`.
+    pub fn of<R>(data: &R::Response) -> Result<Self, ErrorMessage>
+    where
+        R: Rpc,
+    {
+        Self::new(R::RESPONSE_KIND, data)
+    }
+
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn data(&self) -> &DynamicMap {
+        &self.data
+    }
+
+    pub(crate) fn into_parts(self) -> (String, DynamicMap) {
+        (self.kind, self.data)
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{
+    serialization::serialize_message_content, ErrorKind, ErrorMessage, Message, MessageBody,
+    MessageContent, MessageContext,
+};
+
+/// A request tied to the type and kind of its response, e.g. `seq-kv`'s `cas` and `cas_ok`. The same type is
+/// used to serve the request with `MaelstromService::serve` and to call other nodes with `MessageContext::call`.
+pub trait Rpc: Serialize + DeserializeOwned {
+    const KIND: &'static str;
+    const RESPONSE_KIND: &'static str;
+
+    type Response: Serialize + DeserializeOwned;
+}
+
+/// Decodes a reply to `R`: either its response or the error the other node replied with.
+pub fn decode_response<R>(reply: &MessageContext) -> Result<R::Response, ErrorMessage>
+where
+    R: Rpc,
+{
+    match reply.message_kind() {
+        kind if kind == R::RESPONSE_KIND => reply.message_content(),
+        "error" => Err(reply.message_content::<ErrorMessage>()?),
+        kind => Err(ErrorMessage::new(
+            ErrorKind::MalformedRequest,
+            &format!("unexpected reply `{kind}` to `{}`", R::KIND),
+        )),
+    }
+}
+
+/// Called with the context of the reply to a request sent with `rpc`. The reply itself is never answered, so
+/// an error returned from here is only logged.
+pub type ReplyCallback = Box<dyn FnOnce(&MessageContext) -> Result<(), ErrorMessage> + Send>;
+
+struct PendingRequest {
+    dest: String,
+    deadline: Option<Instant>,
+    callback: ReplyCallback,
+}
+
+/// Requests sent by this node that are still waiting for a reply, keyed by their `msg_id`.
+#[derive(Clone, Default)]
+pub struct PendingRequests {
+    requests: Arc<Mutex<HashMap<usize, PendingRequest>>>,
+    timeout: Arc<Mutex<Option<Duration>>>,
+}
+
+impl PendingRequests {
+    /// Gives up on requests registered from now on once they've waited for `timeout`, see [`Self::expire`].
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        if let Ok(mut current) = self.timeout.lock() {
+            *current = timeout;
+        }
+    }
+
+    pub fn register(&self, msg_id: usize, dest: &str, callback: ReplyCallback) {
+        let timeout = self.timeout.lock().ok().and_then(|timeout| *timeout);
+        let request = PendingRequest {
+            dest: dest.to_owned(),
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            callback,
+        };
+
+        if let Ok(mut requests) = self.requests.lock() {
+            requests.insert(msg_id, request);
+        }
+    }
+
+    pub fn take(&self, msg_id: usize) -> Option<ReplyCallback> {
+        let request = self.requests.lock().ok()?.remove(&msg_id)?;
+        Some(request.callback)
+    }
+
+    /// Removes the requests whose deadline has passed at `now`, each with a `Timeout` error to pass to its
+    /// callback in place of the reply that never came.
+    pub fn expire(&self, now: Instant) -> Vec<(Message, ReplyCallback)> {
+        let Ok(mut requests) = self.requests.lock() else {
+            return Vec::new();
+        };
+
+        let mut expired = requests
+            .iter()
+            .filter(|(_, request)| request.deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(msg_id, _)| *msg_id)
+            .collect::<Vec<_>>();
+        expired.sort_unstable();
+
+        expired
+            .into_iter()
+            .filter_map(|msg_id| requests.remove(&msg_id).map(|request| (msg_id, request)))
+            .map(|(msg_id, request)| (timeout_reply(msg_id, request.dest), request.callback))
+            .collect()
+    }
+
+    /// Forgets every pending request; their replies are dropped as stray if they still arrive.
+    pub fn clear(&self) {
+        if let Ok(mut requests) = self.requests.lock() {
+            requests.clear();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests
+            .lock()
+            .map(|requests| requests.len())
+            .unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn timeout_reply(msg_id: usize, dest: String) -> Message {
+    let error = ErrorMessage::new(ErrorKind::Timeout, "request timed out");
+
+    Message {
+        src: Some(dest),
+        dest: None,
+        body: MessageBody {
+            msg_id: None,
+            in_reply_to: Some(msg_id),
+            content: MessageContent {
+                kind: "error".to_string(),
+                data: serialize_message_content(&error).unwrap_or_default(),
+            },
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expire() {
+        let pending = PendingRequests::default();
+        pending.register(1, "lin-kv", Box::new(|_| Ok(())));
+
+        pending.set_timeout(Some(Duration::from_secs(1)));
+        pending.register(2, "n2", Box::new(|_| Ok(())));
+
+        let now = Instant::now();
+        assert!(pending.expire(now).is_empty());
+
+        let expired = pending.expire(now + Duration::from_secs(2));
+        assert_eq!(expired.len(), 1);
+
+        let (reply, _) = &expired[0];
+        assert_eq!(reply.src.as_deref(), Some("n2"));
+        assert_eq!(reply.body.in_reply_to, Some(2));
+        assert_eq!(reply.kind(), "error");
+
+        // Requests sent without a timeout wait forever
+        assert_eq!(pending.len(), 1);
+        assert!(pending.take(1).is_some());
+    }
+}
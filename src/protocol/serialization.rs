@@ -1,15 +1,30 @@
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{
+    de::{value::MapDeserializer, DeserializeOwned},
+    Serialize,
+};
 
-use super::{DynamicMap, ErrorKind, ErrorMessage, Message};
+use super::{DynamicMap, ErrorKind, ErrorMessage, Message, MessageContent};
 
 pub fn deserialize_message_content<T>(msg: &Message) -> Result<T, ErrorMessage>
 where
     T: DeserializeOwned,
 {
-    T::deserialize(serde_json::Value::Object(msg.body.content.data.clone())).map_err(|err| {
+    deserialize_content(&msg.body.content)
+}
+
+/// Deserializes straight from the borrowed fields of the content, without copying them into a `Value` first.
+pub fn deserialize_content<T>(content: &MessageContent) -> Result<T, ErrorMessage>
+where
+    T: DeserializeOwned,
+{
+    let fields = content
+        .data
+        .iter()
+        .map(|(key, value)| (key.as_str(), value));
+    T::deserialize(MapDeserializer::<_, serde_json::Error>::new(fields)).map_err(|err| {
         ErrorMessage::new(
             ErrorKind::MalformedRequest,
-            &format!("failed to deserialize message `{}`", msg.body.content.kind),
+            &format!("failed to deserialize message `{}`", content.kind),
         )
         .with_source(err)
     })
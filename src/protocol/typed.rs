@@ -0,0 +1,117 @@
+use serde::de::DeserializeOwned;
+
+use super::{serialization::deserialize_content, ErrorKind, ErrorMessage, MessageContent};
+
+pub use gossip_glomers_derive::MaelstromMessage;
+
+/// A set of message kinds decoded into one type, usually an enum with a variant per kind that's
+/// matched exhaustively by its handler. Implement it with `#[derive(MaelstromMessage)]`.
+pub trait MaelstromMessage: Sized {
+    const KINDS: &'static [&'static str];
+
+    fn from_content(content: &MessageContent) -> Result<Self, ErrorMessage>;
+}
+
+impl MessageContent {
+    pub fn decode<T>(&self) -> Result<T, ErrorMessage>
+    where
+        T: DeserializeOwned,
+    {
+        deserialize_content(self)
+    }
+
+    /// Decodes a single field of the content; a missing field decodes from `null`, so it's only
+    /// accepted for optional values.
+    pub fn decode_field<T>(&self, name: &str) -> Result<T, ErrorMessage>
+    where
+        T: DeserializeOwned,
+    {
+        let value = self.data.get(name).cloned().unwrap_or_default();
+        T::deserialize(value).map_err(|err| {
+            ErrorMessage::new(
+                ErrorKind::MalformedRequest,
+                &format!(
+                    "failed to deserialize field `{name}` of message `{}`",
+                    self.kind
+                ),
+            )
+            .with_source(err)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::protocol::DynamicMap;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Echo {
+        echo: String,
+    }
+
+    #[derive(Debug, PartialEq, MaelstromMessage)]
+    enum Request {
+        Echo(Echo),
+        Generate,
+        Add {
+            delta: u64,
+            note: Option<String>,
+        },
+        #[maelstrom(kind = "topology")]
+        SetTopology,
+    }
+
+    fn content(kind: &str, fields: &[(&str, Value)]) -> MessageContent {
+        MessageContent {
+            kind: kind.to_string(),
+            data: fields
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect::<DynamicMap>(),
+        }
+    }
+
+    #[test]
+    fn test_derived_kinds() {
+        assert_eq!(Request::KINDS, ["echo", "generate", "add", "topology"]);
+    }
+
+    #[test]
+    fn test_derived_from_content() {
+        let echo = content("echo", &[("echo", Value::from("hi"))]);
+        assert_eq!(
+            Request::from_content(&echo).unwrap(),
+            Request::Echo(Echo {
+                echo: "hi".to_string()
+            })
+        );
+
+        let add = content("add", &[("delta", Value::from(3))]);
+        assert_eq!(
+            Request::from_content(&add).unwrap(),
+            Request::Add {
+                delta: 3,
+                note: None
+            }
+        );
+
+        let topology = content("topology", &[]);
+        assert_eq!(
+            Request::from_content(&topology).unwrap(),
+            Request::SetTopology
+        );
+    }
+
+    #[test]
+    fn test_derived_from_content_errors() {
+        let unknown = Request::from_content(&content("read", &[]));
+        assert!(unknown.is_err_and(|x| x.code() == usize::from(ErrorKind::NotSupported)));
+
+        let malformed = Request::from_content(&content("add", &[("delta", Value::from("x"))]));
+        assert!(malformed.is_err_and(|x| x.code() == usize::from(ErrorKind::MalformedRequest)));
+    }
+}
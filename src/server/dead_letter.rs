@@ -0,0 +1,43 @@
+use std::{
+    fmt::Display,
+    io::{self, Write},
+};
+
+/// Where input lines end up that couldn't be handled at all, together with the reason and the raw line.
+pub struct DeadLetters {
+    out: Box<dyn Write + Send>,
+    count: usize,
+}
+
+impl DeadLetters {
+    pub fn new<W>(out: W) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        Self {
+            out: Box::new(out),
+            count: 0,
+        }
+    }
+
+    pub fn record(&mut self, reason: &dyn Display, raw_line: Option<&str>) {
+        self.count += 1;
+
+        let raw_line = raw_line.unwrap_or("<unavailable>");
+        let res =
+            writeln!(self.out, "dead letter ({reason}): {raw_line}").and_then(|_| self.out.flush());
+        if let Err(err) = res {
+            eprintln!("failed to record dead letter: {err}");
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl Default for DeadLetters {
+    fn default() -> Self {
+        Self::new(io::stderr())
+    }
+}
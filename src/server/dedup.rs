@@ -0,0 +1,239 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::protocol::{Message, MessageContext};
+
+#[derive(Clone, Copy, Debug)]
+pub struct DeduplicationConfig {
+    pub capacity: usize, // Maximum number of requests remembered at once, the oldest ones are forgotten first.
+    pub ttl: Duration,   // How long a request is remembered after it has been handled.
+}
+
+impl Default for DeduplicationConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 4096,
+            ttl: Duration::from_secs(60),
+        }
+    }
+}
+
+pub type RequestKey = (String, usize);
+
+#[derive(Debug, PartialEq)]
+pub enum Lookup {
+    /// Seen for the first time; it's marked as in progress until it's inserted or abandoned.
+    New,
+    /// Still being handled, e.g. on the worker pool.
+    InProgress,
+    /// Already handled, with the replies that were sent.
+    Handled(Vec<Message>),
+}
+
+pub struct DeduplicationCache {
+    config: DeduplicationConfig,
+    replies: HashMap<RequestKey, (Instant, Vec<Message>)>,
+    order: VecDeque<RequestKey>,
+    in_progress: HashSet<RequestKey>,
+}
+
+impl DeduplicationCache {
+    pub fn new(config: DeduplicationConfig) -> Self {
+        Self {
+            config,
+            replies: HashMap::new(),
+            order: VecDeque::new(),
+            in_progress: HashSet::new(),
+        }
+    }
+
+    /// Identifies client requests that are safe to replay; replies and `init` are never deduplicated.
+    pub fn key(ctx: &MessageContext) -> Option<RequestKey> {
+        if ctx.message_in_reply_to().is_some() || ctx.message_kind() == "init" {
+            return None;
+        }
+
+        Some((ctx.message_src()?.to_owned(), ctx.message_id()?))
+    }
+
+    pub fn get(&mut self, key: &RequestKey, now: Instant) -> Option<Vec<Message>> {
+        self.evict_expired(now);
+        self.replies.get(key).map(|(_, replies)| replies.clone())
+    }
+
+    /// Looks `key` up before it's handled, and marks it as in progress if it hasn't been seen yet, so that
+    /// a duplicate delivered while the original is still being handled isn't handled again.
+    pub fn begin(&mut self, key: &RequestKey, now: Instant) -> Lookup {
+        if let Some(replies) = self.get(key, now) {
+            return Lookup::Handled(replies);
+        }
+        if !self.in_progress.insert(key.clone()) {
+            return Lookup::InProgress;
+        }
+        Lookup::New
+    }
+
+    /// Forgets that `key` is in progress without remembering any replies, so that it's handled again if retried.
+    pub fn abandon(&mut self, key: &RequestKey) {
+        self.in_progress.remove(key);
+    }
+
+    /// Remembers the deferred reply to `key` once it's sent, or forgets `key` if it got none. Does nothing unless
+    /// `key` is still in progress, e.g. if the cache was cleared in the meantime.
+    pub fn resolve(&mut self, key: RequestKey, reply: Option<&Message>, now: Instant) {
+        if !self.in_progress.contains(&key) {
+            return;
+        }
+        match reply {
+            Some(reply) => self.insert(key, std::slice::from_ref(reply), now),
+            None => self.abandon(&key),
+        }
+    }
+
+    /// Remembers the replies to `key` out of everything the handlers sent while processing it.
+    pub fn insert(&mut self, key: RequestKey, output: &[Message], now: Instant) {
+        self.evict_expired(now);
+        self.in_progress.remove(&key);
+
+        let replies = output
+            .iter()
+            .filter(|msg| {
+                msg.body.in_reply_to == Some(key.1) && msg.dest.as_deref() == Some(key.0.as_str())
+            })
+            .cloned()
+            .collect();
+
+        if self.replies.insert(key.clone(), (now, replies)).is_none() {
+            self.order.push_back(key);
+        }
+
+        while self.order.len() > self.config.capacity {
+            if let Some(key) = self.order.pop_front() {
+                self.replies.remove(&key);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.replies.clear();
+        self.order.clear();
+        self.in_progress.clear();
+    }
+
+    fn evict_expired(&mut self, now: Instant) {
+        while let Some(key) = self.order.front() {
+            match self.replies.get(key) {
+                Some((handled_at, _)) if now.duration_since(*handled_at) < self.config.ttl => break,
+                _ => {
+                    if let Some(key) = self.order.pop_front() {
+                        self.replies.remove(&key);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{MessageBody, MessageContent};
+
+    fn reply(dest: &str, in_reply_to: usize) -> Message {
+        Message {
+            src: Some("n1".to_string()),
+            dest: Some(dest.to_string()),
+            body: MessageBody {
+                msg_id: Some(100),
+                in_reply_to: Some(in_reply_to),
+                content: MessageContent {
+                    kind: "add_ok".to_string(),
+                    data: Default::default(),
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn test_only_replies_are_cached() {
+        let mut cache = DeduplicationCache::new(DeduplicationConfig::default());
+        let now = Instant::now();
+        let key = ("c1".to_string(), 1);
+
+        cache.insert(key.clone(), &[reply("c1", 1), reply("n2", 1)], now);
+
+        assert_eq!(cache.get(&key, now), Some(vec![reply("c1", 1)]));
+        assert_eq!(cache.get(&("c1".to_string(), 2), now), None);
+    }
+
+    #[test]
+    fn test_in_progress() {
+        let mut cache = DeduplicationCache::new(DeduplicationConfig::default());
+        let now = Instant::now();
+        let key = ("c1".to_string(), 1);
+
+        assert_eq!(cache.begin(&key, now), Lookup::New);
+        assert_eq!(cache.begin(&key, now), Lookup::InProgress);
+
+        cache.abandon(&key);
+        assert_eq!(cache.begin(&key, now), Lookup::New);
+
+        cache.insert(key.clone(), &[reply("c1", 1)], now);
+        assert_eq!(
+            cache.begin(&key, now),
+            Lookup::Handled(vec![reply("c1", 1)])
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut cache = DeduplicationCache::new(DeduplicationConfig::default());
+        let now = Instant::now();
+        let key = ("c1".to_string(), 1);
+
+        // Not in progress, so there's nothing waiting for the reply
+        cache.resolve(key.clone(), Some(&reply("c1", 1)), now);
+        assert_eq!(cache.get(&key, now), None);
+
+        assert_eq!(cache.begin(&key, now), Lookup::New);
+        cache.resolve(key.clone(), None, now);
+        assert_eq!(cache.begin(&key, now), Lookup::New);
+
+        cache.resolve(key.clone(), Some(&reply("c1", 1)), now);
+        assert_eq!(cache.get(&key, now), Some(vec![reply("c1", 1)]));
+    }
+
+    #[test]
+    fn test_ttl() {
+        let mut cache = DeduplicationCache::new(DeduplicationConfig {
+            capacity: 10,
+            ttl: Duration::from_secs(5),
+        });
+        let now = Instant::now();
+        let key = ("c1".to_string(), 1);
+
+        cache.insert(key.clone(), &[reply("c1", 1)], now);
+
+        assert!(cache.get(&key, now + Duration::from_secs(4)).is_some());
+        assert!(cache.get(&key, now + Duration::from_secs(5)).is_none());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut cache = DeduplicationCache::new(DeduplicationConfig {
+            capacity: 2,
+            ttl: Duration::from_secs(5),
+        });
+        let now = Instant::now();
+
+        for msg_id in 1..=3 {
+            cache.insert(("c1".to_string(), msg_id), &[reply("c1", msg_id)], now);
+        }
+
+        assert!(cache.get(&("c1".to_string(), 1), now).is_none());
+        assert!(cache.get(&("c1".to_string(), 2), now).is_some());
+        assert!(cache.get(&("c1".to_string(), 3), now).is_some());
+    }
+}
@@ -1,12 +1,200 @@
-use std::collections::HashMap;
+use std::{
+    any::type_name,
+    collections::{BTreeMap, HashMap},
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::protocol::{ErrorKind, ErrorMessage, MessageContext, MessageHandler, Response};
+
+use super::{
+    panic::PanicIsolation,
+    policy::{DispatchPolicy, PanicPolicy},
+    system_messages::InitMessage,
+};
+
+pub type SharedMessageHandler = Arc<Mutex<dyn MessageHandler + Send>>;
+
+/// Adapts a closure from a typed request to a typed response into a handler of a single message kind.
+pub struct FnHandler<Req, Resp, F> {
+    kind: String,
+    reply_kind: String,
+    f: F,
+    _types: PhantomData<fn(Req) -> Resp>,
+}
 
-use crate::protocol::{ErrorKind, ErrorMessage, MessageContext, MessageHandler};
+impl<Req, Resp, F> FnHandler<Req, Resp, F>
+where
+    F: FnMut(&MessageContext, Req) -> Result<Resp, ErrorMessage>,
+{
+    pub fn new(kind: &str, reply_kind: &str, f: F) -> Self {
+        Self {
+            kind: kind.to_owned(),
+            reply_kind: reply_kind.to_owned(),
+            f,
+            _types: PhantomData,
+        }
+    }
+}
 
-use super::system_messages::InitMessage;
+impl<Req, Resp, F> MessageHandler for FnHandler<Req, Resp, F>
+where
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    F: FnMut(&MessageContext, Req) -> Result<Resp, ErrorMessage>,
+{
+    fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+        [self.kind.as_str()].into_iter()
+    }
+
+    fn handle(&mut self, ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+        let resp = (self.f)(ctx, ctx.message_content()?)?;
+        Ok(Some(Response::new(&self.reply_kind, &resp)?))
+    }
+}
+
+enum HandlerSlot {
+    Local {
+        handler: Box<dyn MessageHandler>,
+        poisoned: bool,
+    },
+    Shared(SharedMessageHandler),
+}
+
+impl HandlerSlot {
+    fn local(handler: impl MessageHandler + 'static) -> Self {
+        HandlerSlot::Local {
+            handler: Box::new(handler),
+            poisoned: false,
+        }
+    }
+
+    fn with_handler<R>(
+        &mut self,
+        f: impl FnOnce(&mut dyn MessageHandler) -> Result<R, ErrorMessage>,
+    ) -> Result<R, ErrorMessage> {
+        match self {
+            HandlerSlot::Local { poisoned: true, .. } => Err(poisoned_error()),
+            HandlerSlot::Local { handler, .. } => f(handler.as_mut()),
+            HandlerSlot::Shared(handler) => with_shared_handler(handler, f),
+        }
+    }
+
+    /// Like `with_handler`, but a panic in `f` is caught and handled according to the policy of `isolation`.
+    fn call<R>(
+        &mut self,
+        isolation: &PanicIsolation,
+        f: impl FnOnce(&mut dyn MessageHandler) -> Result<R, ErrorMessage>,
+    ) -> Result<R, ErrorMessage> {
+        match self {
+            HandlerSlot::Local { .. } => {
+                isolation
+                    .catch(|| self.with_handler(f))
+                    .unwrap_or_else(|err| {
+                        if let (HandlerSlot::Local { poisoned, .. }, PanicPolicy::Poison) =
+                            (&mut *self, isolation.policy())
+                        {
+                            *poisoned = true;
+                        }
+                        Err(err)
+                    })
+            }
+            HandlerSlot::Shared(handler) => call_shared_handler(handler, isolation, f),
+        }
+    }
+}
+
+fn poisoned_error() -> ErrorMessage {
+    ErrorMessage::new(ErrorKind::Crash, "message handler poisoned")
+}
+
+pub fn with_shared_handler<R>(
+    handler: &SharedMessageHandler,
+    f: impl FnOnce(&mut dyn MessageHandler) -> Result<R, ErrorMessage>,
+) -> Result<R, ErrorMessage> {
+    let mut handler = handler.lock().map_err(|_| poisoned_error())?;
+    f(&mut *handler)
+}
+
+/// Calls a shared handler, catching a panic outside of its lock: the panic poisons the mutex, which is kept that
+/// way only under `PanicPolicy::Poison`.
+pub fn call_shared_handler<R>(
+    handler: &SharedMessageHandler,
+    isolation: &PanicIsolation,
+    f: impl FnOnce(&mut dyn MessageHandler) -> Result<R, ErrorMessage>,
+) -> Result<R, ErrorMessage> {
+    isolation
+        .catch(|| with_shared_handler(handler, f))
+        .unwrap_or_else(|err| {
+            if isolation.policy() == PanicPolicy::Isolate {
+                handler.clear_poison();
+            }
+            Err(err)
+        })
+}
+
+/// Lets `handler` handle the message and sends the response it returned, if any.
+pub fn handle_and_respond(
+    handler: &mut dyn MessageHandler,
+    ctx: &MessageContext,
+) -> Result<(), ErrorMessage> {
+    match handler.handle(ctx)? {
+        Some(response) => ctx.respond(response),
+        None => Ok(()),
+    }
+}
+
+/// Runs `count` handlers for a single message according to `policy`; `call` invokes the i-th of them.
+pub fn dispatch(
+    policy: DispatchPolicy,
+    ctx: &MessageContext,
+    count: usize,
+    mut call: impl FnMut(usize) -> Result<(), ErrorMessage>,
+) -> Result<(), ErrorMessage> {
+    match policy {
+        DispatchPolicy::Exclusive { .. } | DispatchPolicy::AllMustSucceed => {
+            let checkpoint = ctx.output_len();
+            for i in 0..count {
+                if let Err(err) = call(i) {
+                    ctx.truncate_output(checkpoint);
+                    return Err(err);
+                }
+            }
+            Ok(())
+        }
+        DispatchPolicy::FirstSuccess => {
+            let mut last_err = None;
+            for i in 0..count {
+                let checkpoint = ctx.output_len();
+                match call(i) {
+                    Ok(()) => return Ok(()),
+                    Err(err) => {
+                        ctx.truncate_output(checkpoint);
+                        last_err = Some(err);
+                    }
+                }
+            }
+            last_err.map_or(Ok(()), Err)
+        }
+        DispatchPolicy::FanOut => {
+            let errors = (0..count).filter_map(|i| call(i).err()).collect::<Vec<_>>();
+            ErrorMessage::combine(errors).map_or(Ok(()), Err)
+        }
+    }
+}
+
+const FALLBACK_PATTERN: &str = "*";
 
 pub struct MaelstromServerMessageHandler {
     msg_handlers: HashMap<String, Vec<usize>>,
-    handlers: Vec<Box<dyn MessageHandler>>,
+    handlers: Vec<HandlerSlot>,
+    handler_names: Vec<&'static str>,
+    handler_kinds: Vec<Vec<String>>,
+    collisions: Vec<String>,
+    policy: DispatchPolicy,
+    isolation: PanicIsolation,
 }
 
 impl MaelstromServerMessageHandler {
@@ -14,46 +202,207 @@ impl MaelstromServerMessageHandler {
         Self {
             msg_handlers: HashMap::new(),
             handlers: Vec::new(),
+            handler_names: Vec::new(),
+            handler_kinds: Vec::new(),
+            collisions: Vec::new(),
+            policy: DispatchPolicy::default(),
+            isolation: PanicIsolation::default(),
         }
     }
 
-    pub fn register_handler<T>(&mut self)
+    pub fn policy(&self) -> DispatchPolicy {
+        self.policy
+    }
+
+    pub fn isolation(&self) -> &PanicIsolation {
+        &self.isolation
+    }
+
+    pub fn set_panic_policy(&mut self, policy: PanicPolicy) {
+        self.isolation.set_policy(policy);
+    }
+
+    /// Rebuilds the routing table from the registered handlers, so that switching policies never loses a handler.
+    pub fn set_policy(&mut self, policy: DispatchPolicy) {
+        self.policy = policy;
+
+        self.msg_handlers.clear();
+        self.collisions.clear();
+        for handler_idx in 0..self.handlers.len() {
+            self.route(handler_idx);
+        }
+    }
+
+    /// Fails if `DispatchPolicy::Exclusive { strict: true }` had to leave a message kind to its first handler.
+    pub fn validate(&self) -> Result<(), ErrorMessage> {
+        match self.policy {
+            DispatchPolicy::Exclusive { strict: true } if !self.collisions.is_empty() => {
+                Err(ErrorMessage::new(
+                    ErrorKind::Crash,
+                    &format!(
+                        "message types handled by more than one handler: {}",
+                        self.collisions.join(", ")
+                    ),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Lists the handlers registered for every message kind, in the order they are called.
+    pub fn handled_kinds(&self) -> BTreeMap<String, Vec<&'static str>> {
+        self.msg_handlers
+            .iter()
+            .map(|(kind, idxs)| {
+                let names = idxs.iter().map(|idx| self.handler_names[*idx]).collect();
+                (kind.clone(), names)
+            })
+            .collect()
+    }
+
+    pub fn register_handler<T>(&mut self, handler: T)
+    where
+        T: MessageHandler + 'static,
+    {
+        let msg_types = handler.get_handled_messages().map(String::from).collect();
+        self.register_slot(HandlerSlot::local(handler), type_name::<T>(), msg_types);
+    }
+
+    pub fn register_concurrent_handler<T>(&mut self, handler: T)
+    where
+        T: MessageHandler + Send + 'static,
+    {
+        let msg_types = handler.get_handled_messages().map(String::from).collect();
+        self.register_slot(
+            HandlerSlot::Shared(Arc::new(Mutex::new(handler))),
+            type_name::<T>(),
+            msg_types,
+        );
+    }
+
+    /// Registers a handler for every message kind no other handler claims, regardless of its handled messages.
+    pub fn register_fallback_handler<T>(&mut self, handler: T)
     where
         T: MessageHandler + 'static,
     {
-        let handle_idx = self.handlers.len();
-        self.handlers.push(Box::new(T::new()));
-
-        let msg_types = T::get_handled_messages();
-        for msg_type in msg_types {
-            let k = msg_type.to_owned();
-            if let Some(idxs) = self.msg_handlers.get_mut(&k) {
-                idxs.push(handle_idx);
+        self.register_slot(
+            HandlerSlot::local(handler),
+            type_name::<T>(),
+            vec![FALLBACK_PATTERN.to_owned()],
+        );
+    }
+
+    /// Finds the handlers for `kind`: an exact match wins over patterns like `internal_*`, and the pattern with the
+    /// longest prefix wins over the others. The fallback handler is registered as the `*` pattern, so it comes last.
+    fn resolve<'a>(
+        msg_handlers: &'a HashMap<String, Vec<usize>>,
+        kind: &str,
+    ) -> Option<&'a Vec<usize>> {
+        msg_handlers.get(kind).or_else(|| {
+            msg_handlers
+                .iter()
+                .filter_map(|(pattern, idxs)| {
+                    let prefix = pattern.strip_suffix(FALLBACK_PATTERN)?;
+                    kind.starts_with(prefix).then_some((prefix.len(), idxs))
+                })
+                .max_by_key(|(len, _)| *len)
+                .map(|(_, idxs)| idxs)
+        })
+    }
+
+    fn register_slot(&mut self, slot: HandlerSlot, name: &'static str, msg_types: Vec<String>) {
+        self.handlers.push(slot);
+        self.handler_names.push(name);
+        self.handler_kinds.push(msg_types);
+
+        self.route(self.handlers.len() - 1);
+    }
+
+    /// Adds a handler to the routing table for every kind it handles, according to the current policy.
+    fn route(&mut self, handler_idx: usize) {
+        for k in &self.handler_kinds[handler_idx] {
+            if let Some(idxs) = self.msg_handlers.get_mut(k) {
+                if let DispatchPolicy::Exclusive { .. } = self.policy {
+                    eprintln!(
+                        "warning: message type {k} is handled by more than one handler, keeping {}",
+                        self.handler_names[idxs[0]]
+                    );
+                    self.collisions.push(k.clone());
+                    continue;
+                }
+                idxs.push(handler_idx);
             } else {
-                self.msg_handlers.insert(k, vec![handle_idx]);
+                self.msg_handlers.insert(k.clone(), vec![handler_idx]);
             }
         }
     }
 
+    /// Whether every one of several handlers runs for `kind`, so that each of them may reply to it.
+    pub fn replies_shared(&self, kind: &str) -> bool {
+        matches!(
+            self.policy,
+            DispatchPolicy::FanOut | DispatchPolicy::AllMustSucceed
+        ) && Self::resolve(&self.msg_handlers, kind).is_some_and(|idxs| idxs.len() > 1)
+    }
+
+    /// Returns the handlers for `kind` if all of them can be run off the main loop.
+    pub fn concurrent_handlers(&self, kind: &str) -> Option<Vec<SharedMessageHandler>> {
+        Self::resolve(&self.msg_handlers, kind)?.iter().try_fold(
+            Vec::new(),
+            |mut shared, handler_idx| match &self.handlers[*handler_idx] {
+                HandlerSlot::Shared(handler) => {
+                    shared.push(handler.clone());
+                    Some(shared)
+                }
+                HandlerSlot::Local { .. } => None,
+            },
+        )
+    }
+
     pub fn handle_init(
         &mut self,
         msg: &InitMessage,
         ctx: &MessageContext,
     ) -> Result<(), ErrorMessage> {
         for handler in &mut self.handlers {
-            handler.init(msg.node_id.as_ref(), msg.node_ids.as_slice(), ctx)?;
+            handler.call(&self.isolation, |handler| {
+                handler.init(msg.node_id.as_ref(), msg.node_ids.as_slice(), ctx)
+            })?;
         }
 
         Ok(())
     }
 
+    pub fn reset(&mut self) {
+        for handler in &mut self.handlers {
+            let _ = handler.with_handler(|handler| {
+                handler.reset();
+                Ok(())
+            });
+        }
+    }
+
+    /// Runs a lifecycle hook on every handler, even if some of them fail.
+    pub fn run_hook(
+        &mut self,
+        mut hook: impl FnMut(&mut dyn MessageHandler) -> Result<(), ErrorMessage>,
+    ) -> Result<(), ErrorMessage> {
+        let errors = self
+            .handlers
+            .iter_mut()
+            .filter_map(|handler| handler.call(&self.isolation, &mut hook).err())
+            .collect::<Vec<_>>();
+        ErrorMessage::combine(errors).map_or(Ok(()), Err)
+    }
+
     pub fn handle_message(&mut self, ctx: &MessageContext) -> Result<(), ErrorMessage> {
         let kind = ctx.message_kind();
-        if let Some(handler_idxs) = self.msg_handlers.get(kind) {
-            for handler_idx in handler_idxs {
-                self.handlers[*handler_idx].handle(ctx)?;
-            }
-            Ok(())
+        if let Some(handler_idxs) = Self::resolve(&self.msg_handlers, kind) {
+            let (handlers, isolation) = (&mut self.handlers, &self.isolation);
+            dispatch(self.policy, ctx, handler_idxs.len(), |i| {
+                handlers[handler_idxs[i]]
+                    .call(isolation, |handler| handle_and_respond(handler, ctx))
+            })
         } else {
             Err(ErrorMessage::new(
                 ErrorKind::NotSupported,
@@ -76,21 +425,17 @@ mod tests {
         struct TestHandler;
 
         impl MessageHandler for TestHandler {
-            fn new() -> Self {
-                Self
-            }
-
-            fn get_handled_messages() -> impl Iterator<Item = &'static str> {
+            fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
                 ["test"].into_iter()
             }
 
-            fn handle(&mut self, _ctx: &MessageContext) -> Result<(), ErrorMessage> {
+            fn handle(&mut self, _ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
                 Err(ErrorMessage::new(ErrorKind::Crash, "hello there"))
             }
         }
 
         let mut handler = MaelstromServerMessageHandler::new();
-        handler.register_handler::<TestHandler>();
+        handler.register_handler(TestHandler);
 
         let msg = Message {
             src: None,
@@ -127,21 +472,18 @@ mod tests {
         }
 
         impl MessageHandler for TestHandler1 {
-            fn new() -> Self {
-                Self
-            }
-
-            fn get_handled_messages() -> impl Iterator<Item = &'static str> {
+            fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
                 ["test"].into_iter()
             }
 
-            fn handle(&mut self, ctx: &MessageContext) -> Result<(), ErrorMessage> {
-                ctx.reply(
+            fn handle(&mut self, _ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+                Response::new(
                     "hello",
                     &TestResponseMessage1 {
                         foo: "there".to_owned(),
                     },
                 )
+                .map(Some)
             }
         }
 
@@ -154,27 +496,24 @@ mod tests {
         }
 
         impl MessageHandler for TestHandler2 {
-            fn new() -> Self {
-                Self
-            }
-
-            fn get_handled_messages() -> impl Iterator<Item = &'static str> {
+            fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
                 ["test"].into_iter()
             }
 
-            fn handle(&mut self, ctx: &MessageContext) -> Result<(), ErrorMessage> {
-                ctx.reply(
+            fn handle(&mut self, _ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+                Response::new(
                     "hi",
                     &TestResponseMessage2 {
                         bar: "wassup".to_owned(),
                     },
                 )
+                .map(Some)
             }
         }
 
         let mut handler = MaelstromServerMessageHandler::new();
-        handler.register_handler::<TestHandler1>();
-        handler.register_handler::<TestHandler2>();
+        handler.register_handler(TestHandler1);
+        handler.register_handler(TestHandler2);
 
         let msg = Message {
             src: None,
@@ -214,15 +553,11 @@ mod tests {
         struct TestHandler1;
 
         impl MessageHandler for TestHandler1 {
-            fn new() -> Self {
-                Self
-            }
-
-            fn get_handled_messages() -> impl Iterator<Item = &'static str> {
+            fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
                 ["test"].into_iter()
             }
 
-            fn handle(&mut self, _ctx: &MessageContext) -> Result<(), ErrorMessage> {
+            fn handle(&mut self, _ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
                 Err(ErrorMessage::new(ErrorKind::Crash, "hello there"))
             }
         }
@@ -231,22 +566,18 @@ mod tests {
         struct TestHandler2;
 
         impl MessageHandler for TestHandler2 {
-            fn new() -> Self {
-                Self
-            }
-
-            fn get_handled_messages() -> impl Iterator<Item = &'static str> {
+            fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
                 ["test"].into_iter()
             }
 
-            fn handle(&mut self, _ctx: &MessageContext) -> Result<(), ErrorMessage> {
+            fn handle(&mut self, _ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
                 Err(ErrorMessage::new(ErrorKind::Crash, "hi there"))
             }
         }
 
         let mut handler = MaelstromServerMessageHandler::new();
-        handler.register_handler::<TestHandler1>();
-        handler.register_handler::<TestHandler2>();
+        handler.register_handler(TestHandler1);
+        handler.register_handler(TestHandler2);
 
         let msg = Message {
             src: None,
@@ -270,4 +601,258 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn test_concurrent_handlers() {
+        struct LocalHandler;
+
+        impl MessageHandler for LocalHandler {
+            fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+                ["local", "mixed"].into_iter()
+            }
+
+            fn handle(&mut self, _ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+                Ok(None)
+            }
+        }
+
+        struct SharedHandler;
+
+        impl MessageHandler for SharedHandler {
+            fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+                ["shared", "mixed"].into_iter()
+            }
+
+            fn handle(&mut self, _ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+                Err(ErrorMessage::new(ErrorKind::Crash, "hello there"))
+            }
+        }
+
+        let mut handler = MaelstromServerMessageHandler::new();
+        handler.register_handler(LocalHandler);
+        handler.register_concurrent_handler(SharedHandler);
+
+        assert!(handler.concurrent_handlers("local").is_none());
+        assert!(handler.concurrent_handlers("mixed").is_none());
+        assert!(handler.concurrent_handlers("unknown").is_none());
+
+        let shared = handler.concurrent_handlers("shared").unwrap();
+        assert_eq!(shared.len(), 1);
+
+        let ctx = MessageContext::new(None);
+        let res = with_shared_handler(&shared[0], |handler| handler.handle(&ctx));
+
+        assert!(
+            res.is_err_and(
+                |x| x.code() == usize::from(ErrorKind::Crash) && x.text() == "hello there"
+            )
+        );
+    }
+
+    struct ReplyingHandler;
+
+    impl MessageHandler for ReplyingHandler {
+        fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+            ["test"].into_iter()
+        }
+
+        fn handle(&mut self, _ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+            Response::new("test_ok", &()).map(Some)
+        }
+    }
+
+    struct ReplyingFailingHandler;
+
+    impl MessageHandler for ReplyingFailingHandler {
+        fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+            ["test", "other"].into_iter()
+        }
+
+        fn handle(&mut self, ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+            ctx.reply("partial", &())?;
+            Err(ErrorMessage::new(ErrorKind::Abort, "hello there"))
+        }
+    }
+
+    fn dispatch_test(
+        policy: DispatchPolicy,
+        register: impl FnOnce(&mut MaelstromServerMessageHandler),
+    ) -> (Result<(), ErrorMessage>, Vec<String>) {
+        let mut handler = MaelstromServerMessageHandler::new();
+        handler.set_policy(policy);
+        register(&mut handler);
+
+        let ctx = MessageContext::new(Some(Message {
+            src: Some("c1".to_string()),
+            dest: Some("n1".to_string()),
+            body: MessageBody {
+                msg_id: Some(1),
+                in_reply_to: None,
+                content: MessageContent {
+                    kind: "test".to_string(),
+                    data: Default::default(),
+                },
+            },
+        }));
+        let res = handler.handle_message(&ctx);
+        let kinds = ctx
+            .into_output_iter()
+            .map(|msg| msg.body.content.kind)
+            .collect();
+
+        (res, kinds)
+    }
+
+    #[test]
+    fn test_all_must_succeed_rollback() {
+        let (res, kinds) = dispatch_test(DispatchPolicy::AllMustSucceed, |handler| {
+            handler.register_handler(ReplyingHandler);
+            handler.register_handler(ReplyingFailingHandler);
+        });
+
+        assert!(res.is_err_and(|x| x.text() == "hello there"));
+        assert!(kinds.is_empty());
+    }
+
+    #[test]
+    fn test_first_success() {
+        let (res, kinds) = dispatch_test(DispatchPolicy::FirstSuccess, |handler| {
+            handler.register_handler(ReplyingFailingHandler);
+            handler.register_handler(ReplyingHandler);
+        });
+
+        assert!(res.is_ok());
+        assert_eq!(kinds, vec!["test_ok"]);
+    }
+
+    #[test]
+    fn test_fan_out() {
+        let (res, kinds) = dispatch_test(DispatchPolicy::FanOut, |handler| {
+            handler.register_handler(ReplyingFailingHandler);
+            handler.register_handler(ReplyingHandler);
+            handler.register_handler(ReplyingFailingHandler);
+        });
+
+        assert!(res
+            .is_err_and(|x| x.code() == usize::from(ErrorKind::Abort)
+                && x.text() == "hello there; hello there"));
+        assert_eq!(kinds, vec!["partial", "test_ok", "partial"]);
+    }
+
+    #[test]
+    fn test_exclusive() {
+        let (res, kinds) = dispatch_test(DispatchPolicy::Exclusive { strict: false }, |handler| {
+            handler.register_handler(ReplyingHandler);
+            handler.register_handler(ReplyingFailingHandler);
+        });
+
+        assert!(res.is_ok());
+        assert_eq!(kinds, vec!["test_ok"]);
+    }
+
+    #[test]
+    fn test_exclusive_strict() {
+        let mut handler = MaelstromServerMessageHandler::new();
+        handler.register_handler(ReplyingHandler);
+        handler.register_handler(ReplyingFailingHandler);
+        assert!(handler.validate().is_ok());
+
+        handler.set_policy(DispatchPolicy::Exclusive { strict: false });
+        assert!(handler.validate().is_ok());
+
+        handler.set_policy(DispatchPolicy::Exclusive { strict: true });
+        assert!(handler
+            .validate()
+            .is_err_and(|x| x.text() == "message types handled by more than one handler: test"));
+        assert_eq!(handler.handled_kinds()["test"].len(), 1);
+
+        // Switching back restores the handlers that were left out
+        handler.set_policy(DispatchPolicy::FanOut);
+        assert!(handler.validate().is_ok());
+        assert_eq!(handler.handled_kinds()["test"].len(), 2);
+    }
+
+    #[test]
+    fn test_handled_kinds() {
+        let mut handler = MaelstromServerMessageHandler::new();
+        handler.register_handler(ReplyingHandler);
+        handler.register_handler(ReplyingFailingHandler);
+
+        let kinds = handler.handled_kinds();
+
+        assert_eq!(kinds.keys().collect::<Vec<_>>(), vec!["other", "test"]);
+        assert_eq!(kinds["other"], vec![type_name::<ReplyingFailingHandler>()]);
+        assert_eq!(
+            kinds["test"],
+            vec![
+                type_name::<ReplyingHandler>(),
+                type_name::<ReplyingFailingHandler>()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_patterns_and_fallback() {
+        struct KindHandler {
+            kinds: Vec<&'static str>,
+            reply: &'static str,
+        }
+
+        impl MessageHandler for KindHandler {
+            fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+                self.kinds.iter().copied()
+            }
+
+            fn handle(&mut self, _ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+                Response::new(self.reply, &()).map(Some)
+            }
+        }
+
+        let mut handler = MaelstromServerMessageHandler::new();
+        handler.register_handler(KindHandler {
+            kinds: vec!["internal_sync"],
+            reply: "exact",
+        });
+        handler.register_handler(KindHandler {
+            kinds: vec!["internal_*"],
+            reply: "internal",
+        });
+        handler.register_handler(KindHandler {
+            kinds: vec!["internal_raft_*"],
+            reply: "raft",
+        });
+
+        let handle = |handler: &mut MaelstromServerMessageHandler, kind: &str| {
+            let ctx = MessageContext::new(Some(Message {
+                src: Some("n2".to_string()),
+                dest: Some("n1".to_string()),
+                body: MessageBody {
+                    msg_id: Some(1),
+                    in_reply_to: None,
+                    content: MessageContent {
+                        kind: kind.to_string(),
+                        data: Default::default(),
+                    },
+                },
+            }));
+            handler
+                .handle_message(&ctx)
+                .map(|_| ctx.into_output_iter().next().unwrap().body.content.kind)
+        };
+
+        assert_eq!(handle(&mut handler, "internal_sync").unwrap(), "exact");
+        assert_eq!(handle(&mut handler, "internal_gossip").unwrap(), "internal");
+        assert_eq!(handle(&mut handler, "internal_raft_vote").unwrap(), "raft");
+        assert!(handle(&mut handler, "echo")
+            .is_err_and(|x| x.code() == usize::from(ErrorKind::NotSupported)));
+
+        handler.register_fallback_handler(KindHandler {
+            kinds: vec!["ignored"],
+            reply: "fallback",
+        });
+
+        assert_eq!(handle(&mut handler, "echo").unwrap(), "fallback");
+        assert_eq!(handle(&mut handler, "ignored").unwrap(), "fallback");
+        assert_eq!(handle(&mut handler, "internal_gossip").unwrap(), "internal");
+    }
 }
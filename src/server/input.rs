@@ -0,0 +1,240 @@
+use std::{
+    fmt::Display,
+    io::{self, BufRead, ErrorKind},
+};
+
+use serde::de::DeserializeOwned;
+
+use crate::protocol::{Message, MessageBody, MessageContent};
+
+pub const DEFAULT_MAX_LINE_SIZE: usize = 16 * 1024 * 1024;
+
+/// A line that couldn't be read, see `LineReader`.
+#[derive(Debug)]
+pub enum InputError {
+    /// Reading failed; no more lines are read after this.
+    Io(io::Error),
+    /// The raw line isn't valid UTF-8.
+    InvalidUtf8(Vec<u8>),
+    /// The line is `len` bytes long, of which only the first `max_line_size` are kept.
+    TooLong { len: usize, prefix: Vec<u8> },
+}
+
+impl InputError {
+    /// The part of the offending line that was read, if any.
+    pub fn raw_line(&self) -> Option<&[u8]> {
+        match self {
+            InputError::Io(_) => None,
+            InputError::InvalidUtf8(line) => Some(line),
+            InputError::TooLong { prefix, .. } => Some(prefix),
+        }
+    }
+}
+
+impl Display for InputError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputError::Io(err) => write!(f, "failed to read input: {err}"),
+            InputError::InvalidUtf8(_) => write!(f, "input line is not valid UTF-8"),
+            InputError::TooLong { len, prefix } => write!(
+                f,
+                "input line of {len} bytes exceeds the limit of {} bytes",
+                prefix.len()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InputError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InputError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Splits input into lines without panicking on bad ones: lines that aren't valid UTF-8 or are longer than
+/// `max_line_size` are returned as errors, so the caller can skip them and keep reading.
+pub struct LineReader<R> {
+    inner: R,
+    max_line_size: usize,
+    failed: bool,
+}
+
+impl<R> LineReader<R>
+where
+    R: BufRead,
+{
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            max_line_size: DEFAULT_MAX_LINE_SIZE,
+            failed: false,
+        }
+    }
+
+    pub fn with_max_line_size(self, max_line_size: usize) -> Self {
+        Self {
+            max_line_size,
+            ..self
+        }
+    }
+
+    /// Reads up to the next newline, keeping at most `max_line_size` bytes of it. Returns the kept bytes along
+    /// with the full length of the line, or `None` at the end of the input.
+    fn read_line(&mut self) -> io::Result<Option<(Vec<u8>, usize)>> {
+        let mut line = Vec::new();
+        let mut len = 0;
+
+        loop {
+            let buf = match self.inner.fill_buf() {
+                Ok(buf) => buf,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            if buf.is_empty() {
+                return Ok((len > 0).then_some((line, len)));
+            }
+
+            let newline = buf.iter().position(|&b| b == b'\n');
+            let chunk = &buf[..newline.unwrap_or(buf.len())];
+
+            let keep = chunk
+                .len()
+                .min(self.max_line_size.saturating_sub(line.len()));
+            line.extend_from_slice(&chunk[..keep]);
+            len += chunk.len();
+
+            let consumed = newline.map_or(buf.len(), |i| i + 1);
+            self.inner.consume(consumed);
+
+            if newline.is_some() {
+                return Ok(Some((line, len)));
+            }
+        }
+    }
+}
+
+impl<R> Iterator for LineReader<R>
+where
+    R: BufRead,
+{
+    type Item = Result<String, InputError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let (mut line, len) = match self.read_line() {
+            Ok(line) => line?,
+            Err(err) => {
+                self.failed = true;
+                return Some(Err(InputError::Io(err)));
+            }
+        };
+
+        if len > self.max_line_size {
+            return Some(Err(InputError::TooLong { len, prefix: line }));
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+
+        Some(String::from_utf8(line).map_err(|err| InputError::InvalidUtf8(err.into_bytes())))
+    }
+}
+
+/// Recovers just enough of a line that couldn't be parsed to reply to its sender: `src` and `body.msg_id` have to
+/// be found, `dest` and `type` are taken along if present. Works on truncated JSON as well. Replies are never
+/// salvaged, so that two nodes can't keep answering each other's errors.
+pub fn salvage_request(line: &str) -> Option<Message> {
+    if salvage_field::<usize>(line, "in_reply_to").is_some() {
+        return None;
+    }
+
+    let src = salvage_field::<String>(line, "src")?;
+    let msg_id = salvage_field::<usize>(line, "msg_id")?;
+
+    Some(Message {
+        src: Some(src),
+        dest: salvage_field(line, "dest"),
+        body: MessageBody {
+            msg_id: Some(msg_id),
+            in_reply_to: None,
+            content: MessageContent {
+                kind: salvage_field(line, "type").unwrap_or_default(),
+                data: Default::default(),
+            },
+        },
+    })
+}
+
+/// Finds the first `"key": value` in `line` and parses the value, ignoring everything after it.
+fn salvage_field<T>(line: &str, key: &str) -> Option<T>
+where
+    T: DeserializeOwned,
+{
+    let pattern = format!("\"{key}\"");
+    let mut rest = line;
+
+    while let Some(pos) = rest.find(&pattern) {
+        rest = &rest[pos + pattern.len()..];
+
+        if let Some(value) = rest.trim_start().strip_prefix(':') {
+            let mut values = serde_json::Deserializer::from_str(value).into_iter::<T>();
+            if let Some(Ok(value)) = values.next() {
+                return Some(value);
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_line_reader_skips_bad_lines() {
+        let mut input = b"first\r\n".to_vec();
+        input.extend_from_slice(b"\xff\xfe\n");
+        input.extend_from_slice(b"0123456789abcdef\n");
+        input.extend_from_slice(b"last");
+
+        let lines = LineReader::new(Cursor::new(input))
+            .with_max_line_size(8)
+            .map(|line| line.map_err(|err| err.to_string()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            lines,
+            vec![
+                Ok("first".to_string()),
+                Err("input line is not valid UTF-8".to_string()),
+                Err("input line of 16 bytes exceeds the limit of 8 bytes".to_string()),
+                Ok("last".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_salvage_request() {
+        let truncated =
+            r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":7,"message":[1,2,"#;
+
+        let msg = salvage_request(truncated).unwrap();
+        assert_eq!(msg.src.as_deref(), Some("c1"));
+        assert_eq!(msg.dest.as_deref(), Some("n1"));
+        assert_eq!(msg.body.msg_id, Some(7));
+        assert_eq!(msg.kind(), "broadcast");
+
+        assert!(salvage_request(r#"{"src":"c1","body":{"type":"echo""#).is_none());
+        assert!(salvage_request(r#"{"src":"n2","body":{"msg_id":1,"in_reply_to":3,"#).is_none());
+        assert!(salvage_request("not json at all").is_none());
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::{Arc, Mutex};
+
+use crate::protocol::{
+    ErrorKind, ErrorMessage, Message, MessageContext, Middleware, PendingRequests,
+};
+
+type SharedMiddleware = Arc<Mutex<dyn Middleware + Send>>;
+
+#[derive(Clone, Default)]
+pub struct MiddlewareChain {
+    middlewares: Vec<SharedMiddleware>,
+}
+
+impl MiddlewareChain {
+    pub fn register<T>(&mut self, middleware: T)
+    where
+        T: Middleware + Send + 'static,
+    {
+        self.middlewares.push(Arc::new(Mutex::new(middleware)));
+    }
+
+    /// Lets middlewares that panicked be used again, see `PanicPolicy::Isolate`.
+    pub fn clear_poison(&self) {
+        for middleware in &self.middlewares {
+            middleware.clear_poison();
+        }
+    }
+
+    pub fn run(
+        &self,
+        ctx: &MessageContext,
+        handle: impl FnOnce(&MessageContext) -> Result<(), ErrorMessage>,
+    ) -> Result<(), ErrorMessage> {
+        let mut entered = 0;
+        let mut res = Ok(());
+
+        for middleware in &self.middlewares {
+            res = with_middleware(middleware, |middleware| middleware.before(ctx));
+            if res.is_err() {
+                break;
+            }
+            entered += 1;
+        }
+
+        if res.is_ok() {
+            res = handle(ctx);
+        }
+
+        // Only the middlewares that let the message through get to see the result
+        for middleware in self.middlewares[..entered].iter().rev() {
+            res = with_middleware(middleware, |middleware| Ok(middleware.after(ctx, res)))
+                .and_then(|res| res);
+        }
+
+        res
+    }
+
+    /// Passes the output through every middleware. The callbacks of requests that are dropped are unregistered
+    /// from `pending`, since their replies can't arrive.
+    pub fn outgoing(&self, output: Vec<Message>, pending: &PendingRequests) -> Vec<Message> {
+        if self.middlewares.is_empty() {
+            return output;
+        }
+
+        output
+            .into_iter()
+            .filter_map(|msg| {
+                let msg_id = msg.body.msg_id;
+                let msg = self
+                    .middlewares
+                    .iter()
+                    .rev()
+                    .try_fold(msg, |msg, middleware| {
+                        with_middleware(middleware, |middleware| Ok(middleware.outgoing(msg)))
+                            .ok()
+                            .flatten()
+                    });
+                if let (None, Some(msg_id)) = (&msg, msg_id) {
+                    pending.take(msg_id);
+                }
+                msg
+            })
+            .collect()
+    }
+}
+
+fn with_middleware<R>(
+    middleware: &SharedMiddleware,
+    f: impl FnOnce(&mut dyn Middleware) -> Result<R, ErrorMessage>,
+) -> Result<R, ErrorMessage> {
+    let mut middleware = middleware
+        .lock()
+        .map_err(|_| ErrorMessage::new(ErrorKind::Crash, "middleware poisoned"))?;
+    f(&mut *middleware)
+}
@@ -1,6 +1,21 @@
+mod dead_letter;
+mod dedup;
 mod handler;
+mod input;
+mod middleware;
 mod node;
+mod panic;
+mod peers;
+mod policy;
+mod pool;
 mod service;
+mod shutdown;
 mod system_messages;
+mod writer;
 
+pub use dedup::DeduplicationConfig;
+pub use input::{InputError, LineReader, DEFAULT_MAX_LINE_SIZE};
+pub use policy::*;
 pub use service::*;
+pub use shutdown::ShutdownReport;
+pub use writer::{write_message, OutboxWriter};
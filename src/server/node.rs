@@ -1,21 +1,24 @@
-use crate::protocol::{ErrorMessage, MessageContext};
+use std::sync::Arc;
 
-use super::system_messages::{InitMessage, InitOkMessage};
+use crate::protocol::{ClusterMembership, InitTracker, Message};
 
 pub struct MaelstromServerNode {
-    pub node_id: Option<String>,
-    pub node_ids: Vec<String>,
+    membership: Arc<ClusterMembership>,
 }
 
 impl MaelstromServerNode {
-    pub fn create(ctx: &MessageContext) -> Result<Self, ErrorMessage> {
-        let init_msg = ctx.message_content::<InitMessage>()?;
-
-        let node_id = Some(init_msg.node_id.to_string());
-        let node_ids = init_msg.node_ids;
-
-        ctx.reply("init_ok", &InitOkMessage)?;
+    pub fn new(membership: Arc<ClusterMembership>) -> Self {
+        Self { membership }
+    }
 
-        Ok(Self { node_id, node_ids })
+    pub fn membership(&self) -> &Arc<ClusterMembership> {
+        &self.membership
     }
 }
+
+/// An `init` message whose handlers succeeded, but some of them deferred their initialization.
+pub struct PendingInit {
+    pub msg: Message,
+    pub membership: Arc<ClusterMembership>,
+    pub tracker: InitTracker,
+}
@@ -0,0 +1,55 @@
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::protocol::{ErrorKind, ErrorMessage};
+
+use super::policy::PanicPolicy;
+
+/// Catches panics of handlers, so that a bug in one of them only fails the message it was handling.
+#[derive(Clone, Default)]
+pub struct PanicIsolation {
+    policy: PanicPolicy,
+    panics: Arc<AtomicUsize>,
+}
+
+impl PanicIsolation {
+    pub fn policy(&self) -> PanicPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: PanicPolicy) {
+        self.policy = policy;
+    }
+
+    /// Number of panics caught so far.
+    pub fn count(&self) -> usize {
+        self.panics.load(Ordering::Relaxed)
+    }
+
+    /// Runs `f`, turning a panic into the outer `Crash` error with the panic message.
+    pub fn catch<R>(&self, f: impl FnOnce() -> R) -> Result<R, ErrorMessage> {
+        panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+            self.panics.fetch_add(1, Ordering::Relaxed);
+            ErrorMessage::new(
+                ErrorKind::Crash,
+                &format!("handler panicked: {}", panic_message(payload.as_ref())),
+            )
+        })
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic"
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+/// Tracks when each peer was last heard from, to tell handlers about the ones that went silent.
+pub struct PeerMonitor {
+    threshold: Duration,
+    last_seen: HashMap<String, Instant>,
+    unreachable: HashSet<String>,
+}
+
+impl PeerMonitor {
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            last_seen: HashMap::new(),
+            unreachable: HashSet::new(),
+        }
+    }
+
+    /// Starts watching `peers` as if all of them had just been heard from.
+    pub fn watch<'a>(&mut self, peers: impl IntoIterator<Item = &'a str>, now: Instant) {
+        self.clear();
+        self.last_seen = peers
+            .into_iter()
+            .map(|peer| (peer.to_owned(), now))
+            .collect();
+    }
+
+    pub fn seen(&mut self, peer: &str, now: Instant) {
+        if let Some(last_seen) = self.last_seen.get_mut(peer) {
+            *last_seen = now;
+            self.unreachable.remove(peer);
+        }
+    }
+
+    /// Returns the peers that went silent for longer than the threshold since the last call, each of them only
+    /// once until it's heard from again.
+    pub fn newly_unreachable(&mut self, now: Instant) -> Vec<String> {
+        let mut peers = self
+            .last_seen
+            .iter()
+            .filter(|(peer, last_seen)| {
+                now.saturating_duration_since(**last_seen) > self.threshold
+                    && !self.unreachable.contains(*peer)
+            })
+            .map(|(peer, _)| peer.clone())
+            .collect::<Vec<_>>();
+        peers.sort();
+
+        self.unreachable.extend(peers.iter().cloned());
+        peers
+    }
+
+    pub fn clear(&mut self) {
+        self.last_seen.clear();
+        self.unreachable.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_newly_unreachable() {
+        let start = Instant::now();
+        let mut monitor = PeerMonitor::new(Duration::from_secs(1));
+        monitor.watch(["n2", "n3"], start);
+
+        assert!(monitor
+            .newly_unreachable(start + Duration::from_millis(500))
+            .is_empty());
+
+        monitor.seen("n3", start + Duration::from_millis(900));
+        monitor.seen("c1", start + Duration::from_millis(900)); // Not a peer, ignored
+
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(monitor.newly_unreachable(later), vec!["n2"]);
+        assert!(monitor.newly_unreachable(later).is_empty());
+
+        monitor.seen("n2", later);
+        let much_later = start + Duration::from_secs(3);
+        assert_eq!(monitor.newly_unreachable(much_later), vec!["n2", "n3"]);
+    }
+}
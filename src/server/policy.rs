@@ -0,0 +1,67 @@
+/// What the service does with non-`init` messages that arrive before the node has been initialized.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PreInitPolicy {
+    /// Pass them on to the handlers, which have to cope with the missing node id themselves.
+    #[default]
+    Deliver,
+    /// Reply with `TemporarilyUnavailable` without calling the handlers.
+    Reject,
+    /// Queue them and handle them right after `init`, rejecting anything past `capacity`.
+    Buffer { capacity: usize },
+}
+
+/// What the service does with an `init` message once the node has already been initialized.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReinitPolicy {
+    /// Reply with `PreconditionFailed` and keep the current membership.
+    #[default]
+    Reject,
+    /// Reply with `init_ok` again if the membership is unchanged, otherwise reject it.
+    IgnoreIdentical,
+    /// Call `reset` on every handler, forget the node state and the requests waiting for a reply, and initialize
+    /// the node from scratch. Extensions are kept, since they're usually set up along with the service.
+    Reset,
+}
+
+/// How the handlers registered for the same message kind are run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DispatchPolicy {
+    /// A kind belongs to the first handler registered for it. Registering another one logs a warning, and makes
+    /// `MaelstromService::validate` fail when `strict` is set.
+    Exclusive { strict: bool },
+    /// Handlers are tried in registration order until one succeeds; replies from the failed ones are dropped.
+    FirstSuccess,
+    /// Handlers run in registration order and the first error stops dispatch and drops every queued reply.
+    #[default]
+    AllMustSucceed,
+    /// Every handler runs; their replies are kept and their errors are combined into one.
+    FanOut,
+}
+
+/// What happens to a handler after it panicked. Either way the panic is caught and the message it was handling
+/// is answered with a `Crash` error.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Keep calling the handler for later messages, assuming the panic didn't leave it in a broken state.
+    #[default]
+    Isolate,
+    /// Stop calling the handler; the messages it would have handled are answered with a `Crash` error instead.
+    Poison,
+}
+
+/// What the service does when a client request isn't answered with exactly one reply or error, and hasn't been
+/// deferred with `MessageContext::defer_reply` either. Messages from other nodes of the cluster aren't checked.
+/// Kinds that several handlers run for under `FanOut` or `AllMustSucceed` only need at least one reply, since
+/// each of those handlers may answer. Defaults to `Strict` in debug builds, so tests catch violations, and to
+/// `Warn` in release builds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReplyPolicy {
+    /// Send whatever the handlers produced.
+    Unchecked,
+    /// Send whatever the handlers produced and log the violation.
+    #[cfg_attr(not(debug_assertions), default)]
+    Warn,
+    /// Drop the replies and answer with a `Crash` error instead.
+    #[cfg_attr(debug_assertions, default)]
+    Strict,
+}
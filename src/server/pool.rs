@@ -0,0 +1,113 @@
+use std::{
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use super::shutdown::wait_until;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct WorkerPool {
+    tx: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(threads: usize) -> Self {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let rx = rx.clone();
+                thread::spawn(move || loop {
+                    // The lock is released as soon as a job is received, so other workers can pick up the next one
+                    let job = match rx.lock() {
+                        Ok(rx) => rx.recv(),
+                        Err(_) => return,
+                    };
+
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            tx: Some(tx),
+            workers,
+        }
+    }
+
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(Box::new(job));
+        }
+    }
+
+    /// Lets the workers finish the queued jobs, but leaves them running detached if they haven't by `deadline`.
+    /// Returns whether all jobs were finished.
+    pub fn join_before(mut self, deadline: Instant) -> bool {
+        self.tx.take();
+
+        if wait_until(deadline, || {
+            self.workers.iter().all(|worker| worker.is_finished())
+        }) {
+            true
+        } else {
+            self.workers.clear();
+            false
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // Closing the channel lets the workers finish the queued jobs and exit
+        self.tx.take();
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Barrier,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_jobs_run_concurrently() {
+        let pool = WorkerPool::new(2);
+        let barrier = Arc::new(Barrier::new(2));
+        let finished = Arc::new(AtomicUsize::new(0));
+
+        // Both jobs wait on the same barrier, so this would deadlock if they ran one after another
+        for _ in 0..2 {
+            let barrier = barrier.clone();
+            let finished = finished.clone();
+            pool.execute(move || {
+                barrier.wait();
+                finished.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        drop(pool);
+
+        assert_eq!(finished.load(Ordering::SeqCst), 2);
+    }
+}
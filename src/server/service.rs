@@ -1,52 +1,724 @@
-use serde::{Deserialize, Deserializer};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::Write,
+    sync::{mpsc::Receiver, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
 
-use crate::protocol::{ErrorKind, ErrorMessage, Message, MessageContext, MessageHandler};
+use crate::protocol::{
+    ClusterMembership, DeferredReplies, ErrorKind, ErrorMessage, Extensions, InitStatus,
+    InitTracker, Message, MessageContext, MessageHandler, MessageIdAllocator, Middleware, Outbox,
+    PendingRequests, ReplyCallback, Rpc,
+};
 
 use super::{
-    handler::MaelstromServerMessageHandler, node::MaelstromServerNode, system_messages::InitMessage,
+    dead_letter::DeadLetters,
+    dedup::{DeduplicationCache, DeduplicationConfig, Lookup},
+    handler::{
+        call_shared_handler, dispatch, handle_and_respond, FnHandler, MaelstromServerMessageHandler,
+    },
+    input::{salvage_request, InputError},
+    middleware::MiddlewareChain,
+    node::{MaelstromServerNode, PendingInit},
+    panic::PanicIsolation,
+    peers::PeerMonitor,
+    policy::{DispatchPolicy, PanicPolicy, PreInitPolicy, ReinitPolicy, ReplyPolicy},
+    pool::WorkerPool,
+    shutdown::ShutdownReport,
+    system_messages::{InitMessage, InitOkMessage},
+    writer::OutboxWriter,
 };
 
 pub struct MaelstromService {
     handler: MaelstromServerMessageHandler,
     node: Option<MaelstromServerNode>,
+    pending_init: Option<PendingInit>,
+    outbox: Outbox,
+    outbox_rx: Option<Receiver<Message>>,
+    writer: Option<OutboxWriter>,
+    msg_ids: MessageIdAllocator,
+    pool: Option<WorkerPool>,
+    dedup: Option<Arc<Mutex<DeduplicationCache>>>,
+    pre_init: PreInitPolicy,
+    pre_init_queue: VecDeque<Message>,
+    reinit: ReinitPolicy,
+    replies: ReplyPolicy,
+    middlewares: MiddlewareChain,
+    extensions: Extensions,
+    pending: PendingRequests,
+    deferred: DeferredReplies,
+    tick_interval: Option<Duration>,
+    last_tick: Option<Instant>,
+    peers: Option<PeerMonitor>,
+    dead_letters: DeadLetters,
 }
 
 impl MaelstromService {
     pub fn new() -> Self {
+        Self::with_message_ids(MessageIdAllocator::default())
+    }
+
+    pub fn with_message_ids(msg_ids: MessageIdAllocator) -> Self {
+        let pending = PendingRequests::default();
+        let (outbox, outbox_rx) = Outbox::new(msg_ids.clone(), pending.clone());
+
         Self {
             handler: MaelstromServerMessageHandler::new(),
             node: None,
+            pending_init: None,
+            outbox,
+            outbox_rx: Some(outbox_rx),
+            writer: None,
+            msg_ids,
+            pool: None,
+            dedup: None,
+            pre_init: PreInitPolicy::default(),
+            pre_init_queue: VecDeque::new(),
+            reinit: ReinitPolicy::default(),
+            replies: ReplyPolicy::default(),
+            middlewares: MiddlewareChain::default(),
+            extensions: Extensions::default(),
+            pending,
+            deferred: DeferredReplies::default(),
+            tick_interval: None,
+            last_tick: None,
+            peers: None,
+            dead_letters: DeadLetters::default(),
+        }
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.node.is_some()
+    }
+
+    pub fn message_ids(&self) -> &MessageIdAllocator {
+        &self.msg_ids
+    }
+
+    /// Values shared by all handlers, e.g. a KV client or state owned by one handler and read by others.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Requests sent with `rpc` that have not been replied to yet.
+    pub fn pending_requests(&self) -> &PendingRequests {
+        &self.pending
+    }
+
+    /// Requests whose reply was deferred with `MessageContext::defer_reply` and hasn't been sent yet.
+    pub fn deferred_replies(&self) -> &DeferredReplies {
+        &self.deferred
+    }
+
+    pub fn outbox(&self) -> Outbox {
+        self.outbox.clone()
+    }
+
+    pub fn drain_outbox(&self) -> impl Iterator<Item = Message> + '_ {
+        self.outbox_rx.iter().flat_map(|rx| rx.try_iter())
+    }
+
+    /// Moves the outbox onto a dedicated thread that writes every message posted to it into `out`, until it's
+    /// flushed by [`Self::shutdown`]. Does nothing if the writer has already been spawned.
+    pub fn spawn_outbox_writer<W>(&mut self, out: W)
+    where
+        W: Write + Send + 'static,
+    {
+        if let Some(rx) = self.outbox_rx.take() {
+            self.writer = Some(OutboxWriter::spawn(rx, out));
+        }
+    }
+
+    /// Runs messages handled exclusively by concurrent handlers on a pool of `threads` workers.
+    /// Their replies are posted to the outbox instead of being returned from [`Self::input`].
+    pub fn enable_worker_pool(&mut self, threads: usize) {
+        self.pool = Some(WorkerPool::new(threads));
+    }
+
+    /// Waits for the messages already handed to the worker pool to be handled.
+    pub fn join_worker_pool(&mut self) {
+        self.pool.take();
+    }
+
+    /// Remembers the replies to handled requests and sends them again when the same `(src, msg_id)`
+    /// is delivered twice, without calling the handlers. Duplicates that arrive while the original is still
+    /// being handled are dropped, including while its reply is deferred. Only successful replies are remembered.
+    pub fn enable_deduplication(&mut self, config: DeduplicationConfig) {
+        let dedup = Arc::new(Mutex::new(DeduplicationCache::new(config)));
+
+        let cache = dedup.clone();
+        self.deferred.set_on_resolve(move |dest, msg_id, reply| {
+            if let Ok(mut cache) = cache.lock() {
+                let reply = reply.filter(|reply| reply.kind() != "error");
+                cache.resolve((dest.to_owned(), msg_id), reply, Instant::now());
+            }
+        });
+
+        self.dedup = Some(dedup);
+    }
+
+    pub fn set_pre_init_policy(&mut self, policy: PreInitPolicy) {
+        self.pre_init = policy;
+    }
+
+    pub fn set_reinit_policy(&mut self, policy: ReinitPolicy) {
+        self.reinit = policy;
+    }
+
+    pub fn set_dispatch_policy(&mut self, policy: DispatchPolicy) {
+        self.handler.set_policy(policy);
+    }
+
+    /// Checks the registered handlers against the dispatch policy; call it once everything is registered.
+    pub fn validate(&self) -> Result<(), ErrorMessage> {
+        self.handler.validate()
+    }
+
+    /// Decides whether a handler keeps being called after it panicked. Panics are always caught and answered
+    /// with a `Crash` error, so the node keeps serving the other message kinds.
+    pub fn set_panic_policy(&mut self, policy: PanicPolicy) {
+        self.handler.set_panic_policy(policy);
+    }
+
+    /// Number of panics caught so far in handlers, middlewares and reply callbacks.
+    pub fn isolated_panics(&self) -> usize {
+        self.handler.isolation().count()
+    }
+
+    pub fn set_reply_policy(&mut self, policy: ReplyPolicy) {
+        self.replies = policy;
+    }
+
+    /// Makes `tick` fail the requests sent from now on that haven't been replied to within `timeout`: their
+    /// callbacks get a `Timeout` error instead of the reply.
+    pub fn set_rpc_timeout(&mut self, timeout: Duration) {
+        self.pending.set_timeout(Some(timeout));
+    }
+
+    /// Makes `tick` call `on_tick` on every handler at most once per `interval`.
+    pub fn set_tick_interval(&mut self, interval: Duration) {
+        self.tick_interval = Some(interval);
+    }
+
+    pub fn tick_interval(&self) -> Option<Duration> {
+        self.tick_interval
+    }
+
+    /// Makes `tick` call `on_peer_unreachable` for peers that haven't sent anything for longer than `threshold`.
+    pub fn set_peer_timeout(&mut self, threshold: Duration) {
+        let mut monitor = PeerMonitor::new(threshold);
+        if let Some(node) = &self.node {
+            monitor.watch(node.membership().peers(), Instant::now());
         }
+        self.peers = Some(monitor);
+    }
+
+    pub fn handled_kinds(&self) -> BTreeMap<String, Vec<&'static str>> {
+        self.handler.handled_kinds()
     }
 
     #[allow(private_bounds)]
     pub fn register_handler<T>(&mut self)
+    where
+        T: MessageHandler + Default + 'static,
+    {
+        self.register_handler_instance(T::default())
+    }
+
+    /// Registers an already configured handler, so that the same handler type can be registered more than once.
+    pub fn register_handler_instance<T>(&mut self, handler: T)
+    where
+        T: MessageHandler + 'static,
+    {
+        self.handler.register_handler(handler)
+    }
+
+    /// Handles `kind` with a closure that turns the request content into the content of the `<kind>_ok` reply.
+    pub fn on<Req, Resp>(
+        &mut self,
+        kind: &str,
+        f: impl FnMut(&MessageContext, Req) -> Result<Resp, ErrorMessage> + 'static,
+    ) where
+        Req: DeserializeOwned + 'static,
+        Resp: Serialize + 'static,
+    {
+        self.register_handler_instance(FnHandler::new(kind, &format!("{kind}_ok"), f))
+    }
+
+    /// Like `on`, but with the kinds and types of the request and its response taken from `R`.
+    pub fn serve<R>(
+        &mut self,
+        f: impl FnMut(&MessageContext, R) -> Result<R::Response, ErrorMessage> + 'static,
+    ) where
+        R: Rpc + 'static,
+    {
+        self.register_handler_instance(FnHandler::new(R::KIND, R::RESPONSE_KIND, f))
+    }
+
+    /// Registers a handler that receives every message no other handler claims, e.g. to forward it to a leader.
+    /// Handlers can also claim a family of kinds with a pattern like `internal_*`, which takes precedence over this.
+    pub fn register_fallback_handler<T>(&mut self)
+    where
+        T: MessageHandler + Default + 'static,
+    {
+        self.register_fallback_handler_instance(T::default())
+    }
+
+    pub fn register_fallback_handler_instance<T>(&mut self, handler: T)
     where
         T: MessageHandler + 'static,
     {
-        self.handler.register_handler::<T>()
+        self.handler.register_fallback_handler(handler)
+    }
+
+    pub fn register_concurrent_handler<T>(&mut self)
+    where
+        T: MessageHandler + Default + Send + 'static,
+    {
+        self.register_concurrent_handler_instance(T::default())
+    }
+
+    pub fn register_concurrent_handler_instance<T>(&mut self, handler: T)
+    where
+        T: MessageHandler + Send + 'static,
+    {
+        self.handler.register_concurrent_handler(handler)
+    }
+
+    pub fn register_middleware<T>(&mut self)
+    where
+        T: Middleware + Default + Send + 'static,
+    {
+        self.register_middleware_instance(T::default())
+    }
+
+    /// Middlewares see every message the node sends: the output of `input` and `tick`, and what handlers send
+    /// through the outbox or as deferred replies. Only messages handed to `Outbox::post` directly skip them.
+    pub fn register_middleware_instance<T>(&mut self, middleware: T)
+    where
+        T: Middleware + Send + 'static,
+    {
+        self.middlewares.register(middleware);
+
+        let middlewares = self.middlewares.clone();
+        let pending = self.pending.clone();
+        self.outbox
+            .set_outgoing(move |msg| middlewares.outgoing(vec![msg], &pending).pop());
+    }
+
+    /// Sends the lines that can't be handled at all to `out` instead of stderr, e.g. to a dead-letter file.
+    pub fn set_dead_letter_output<W>(&mut self, out: W)
+    where
+        W: Write + Send + 'static,
+    {
+        self.dead_letters = DeadLetters::new(out);
+    }
+
+    /// Number of input lines that couldn't be handled at all.
+    pub fn dead_letters(&self) -> usize {
+        self.dead_letters.count()
     }
 
+    /// Handles a message that's already been read. If it can't be deserialized, it's recorded as a dead letter
+    /// without a reply, since its sender is unknown; prefer [`Self::input_line`] when the raw line is available.
     pub fn input<'de, D>(&mut self, deserializer: D) -> impl Iterator<Item = Message>
     where
         D: Deserializer<'de>,
     {
-        let message = Message::deserialize(deserializer);
+        self.input_at(deserializer, Instant::now())
+    }
+
+    /// Like [`Self::input`], with the message received at `now` on the same clock that's passed to [`Self::tick`].
+    pub fn input_at<'de, D>(
+        &mut self,
+        deserializer: D,
+        now: Instant,
+    ) -> impl Iterator<Item = Message>
+    where
+        D: Deserializer<'de>,
+    {
+        let mut output = match Message::deserialize(deserializer) {
+            Ok(msg) => self.dispatch(msg, now),
+            Err(err) => {
+                self.dead_letters.record(&err, None);
+                Vec::new()
+            }
+        };
+
+        output.extend(self.poll_init(now));
+
+        output.into_iter()
+    }
+
+    /// Handles a raw input line. Lines that can't be deserialized are recorded as dead letters, and answered
+    /// with `MalformedRequest` only if the node is initialized and their sender and `msg_id` can be salvaged.
+    pub fn input_line(&mut self, line: &str) -> impl Iterator<Item = Message> {
+        self.input_line_at(line, Instant::now())
+    }
+
+    /// Like [`Self::input_line`], with the line received at `now` on the same clock that's passed to [`Self::tick`].
+    pub fn input_line_at(&mut self, line: &str, now: Instant) -> impl Iterator<Item = Message> {
+        let mut output = match serde_json::from_str::<Message>(line) {
+            Ok(msg) => self.dispatch(msg, now),
+            Err(err) => self.dead_letter(&err.to_string(), Some(line)),
+        };
+
+        output.extend(self.poll_init(now));
 
-        let ctx = message.map(|msg| MessageContext::new(Some(msg)));
+        output.into_iter()
+    }
+
+    /// Runs the periodic hooks that are due at `now`; the input loop calls this between messages. It also fails
+    /// the requests that timed out and finishes an `init` whose deferred handlers completed in the meantime, e.g.
+    /// on another thread. No hooks run before the node is initialized.
+    pub fn tick(&mut self, now: Instant) -> impl Iterator<Item = Message> {
+        let mut output = Vec::new();
+        for (reply, on_reply) in self.pending.expire(now) {
+            output.extend(self.run_reply_callback(reply, on_reply));
+        }
+        output.extend(self.poll_init(now));
+        if self.node.is_none() {
+            return output.into_iter();
+        }
+
+        let ctx = self.context_for(None);
+
+        let due = match (self.tick_interval, self.last_tick) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(interval), Some(last_tick)) => {
+                now.saturating_duration_since(last_tick) >= interval
+            }
+        };
+        if due {
+            self.last_tick = Some(now);
+            if let Err(error) = self.handler.run_hook(|handler| handler.on_tick(&ctx)) {
+                eprintln!("failed to handle tick: {error}");
+            }
+        }
+
+        let unreachable = self
+            .peers
+            .as_mut()
+            .map(|monitor| monitor.newly_unreachable(now))
+            .unwrap_or_default();
+        for peer in unreachable {
+            let res = self
+                .handler
+                .run_hook(|handler| handler.on_peer_unreachable(&peer, &ctx));
+            if let Err(error) = res {
+                eprintln!("failed to handle unreachable peer {peer}: {error}");
+            }
+        }
+
+        output.extend(
+            self.middlewares
+                .outgoing(ctx.into_output_iter().collect(), &self.pending),
+        );
 
-        let res = ctx
-            .as_ref()
-            .map_err(|err| ErrorMessage::new(ErrorKind::MalformedRequest, &format!("{}", err)))
-            .and_then(|ctx| self.handle(ctx));
+        output.into_iter()
+    }
 
-        let ctx = ctx.unwrap_or_default();
+    /// Shuts the node down once the input has ended: runs `on_shutdown` on every handler, waits for the worker
+    /// pool and writes out the outbox. Whatever isn't done within `timeout` is given up on and reported as lost.
+    /// Without a writer, the outbox is left for [`Self::drain_outbox`].
+    pub fn shutdown(&mut self, timeout: Duration) -> ShutdownReport {
+        let deadline = Instant::now() + timeout;
+        let mut report = ShutdownReport {
+            unhandled_messages: self.pre_init_queue.len(),
+            ..Default::default()
+        };
 
+        let ctx = self.context_for(None);
+        let res = self.handler.run_hook(|handler| {
+            if Instant::now() >= deadline {
+                report.skipped_hooks += 1;
+                return Ok(());
+            }
+            handler.on_shutdown(&ctx)
+        });
         if let Err(error) = res {
-            let _ = ctx.error(&error);
+            eprintln!("failed to shut down: {error}");
+            report.failed_hooks = true;
+        }
+
+        for msg in self
+            .middlewares
+            .outgoing(ctx.into_output_iter().collect(), &self.pending)
+        {
+            let _ = self.outbox.post(msg);
+        }
+
+        let pool_joined = self
+            .pool
+            .take()
+            .is_none_or(|pool| pool.join_before(deadline));
+        let written = match self
+            .writer
+            .take()
+            .map(|writer| writer.finish_before(deadline))
+        {
+            None | Some(Ok(true)) => true,
+            Some(Ok(false)) => false,
+            Some(Err(error)) => {
+                eprintln!("failed to write output: {error}");
+                false
+            }
+        };
+        report.flushed = pool_joined && written;
+
+        // Replies can't arrive anymore, so whatever is still pending is lost
+        report.unanswered_requests = self.pending.len();
+        report.unsent_replies = self.deferred.len();
+
+        if !report.is_clean() {
+            eprintln!("shut down with work left undone: {report:?}");
+        }
+
+        report
+    }
+
+    /// Skips a line the input reader rejected. The sender gets a `MalformedRequest` error if it can be recovered
+    /// from what was read of the line, once the node is initialized.
+    pub fn reject_input(&mut self, error: &InputError) -> impl Iterator<Item = Message> {
+        let raw_line = error.raw_line().map(String::from_utf8_lossy);
+        self.dead_letter(&error.to_string(), raw_line.as_deref())
+            .into_iter()
+    }
+
+    /// Records a line that couldn't be handled, and replies to its sender if it can be salvaged from the line.
+    fn dead_letter(&mut self, reason: &str, raw_line: Option<&str>) -> Vec<Message> {
+        self.dead_letters.record(&reason, raw_line);
+
+        // Before init there's no node id to reply from
+        if self.membership().is_none() {
+            return Vec::new();
+        }
+
+        let Some(msg) = raw_line.and_then(salvage_request) else {
+            return Vec::new();
+        };
+
+        let ctx = self.context(msg);
+        let _ = ctx.error(&ErrorMessage::new(ErrorKind::MalformedRequest, reason));
+
+        self.middlewares
+            .outgoing(ctx.into_output_iter().collect(), &self.pending)
+    }
+
+    fn dispatch(&mut self, msg: Message, now: Instant) -> Vec<Message> {
+        if let (Some(monitor), Some(src)) = (&mut self.peers, msg.src.as_deref()) {
+            monitor.seen(src, now);
+        }
+
+        // Replies skip the pre-init policy, since handlers may be waiting for them to finish a deferred init
+        if let Some(in_reply_to) = msg.body.in_reply_to {
+            return self.route_reply(msg, in_reply_to);
+        }
+
+        if self.node.is_none() && msg.kind() != "init" {
+            match self.pre_init {
+                PreInitPolicy::Deliver => {}
+                PreInitPolicy::Buffer { capacity } if self.pre_init_queue.len() < capacity => {
+                    self.pre_init_queue.push_back(msg);
+                    return Vec::new();
+                }
+                PreInitPolicy::Reject | PreInitPolicy::Buffer { .. } => {
+                    let ctx = self.context(msg);
+                    let _ = ctx.error(&ErrorMessage::new(
+                        ErrorKind::TemporarilyUnavailable,
+                        "node not initialized",
+                    ));
+                    return self
+                        .middlewares
+                        .outgoing(ctx.into_output_iter().collect(), &self.pending);
+                }
+            }
+        }
+
+        let ctx = self.context(msg);
+
+        if let Some(replies) = self.deduplicate(&ctx, now) {
+            return replies;
+        }
+
+        let Some(ctx) = self.offload(ctx) else {
+            return Vec::new();
+        };
+
+        let middlewares = self.middlewares.clone();
+        let isolation = self.handler.isolation().clone();
+        let res = run_isolated(&middlewares, &isolation, &ctx, |ctx| self.handle(ctx));
+
+        let shared_replies = self.handler.replies_shared(ctx.message_kind());
+        finish(
+            ctx,
+            res,
+            self.replies,
+            shared_replies,
+            &middlewares,
+            &self.pending,
+            self.dedup.as_ref(),
+        )
+    }
+
+    /// Hands a reply over to the callback of the request it answers. Replies are never answered themselves,
+    /// so errors and replies to unknown requests are only logged.
+    fn route_reply(&mut self, msg: Message, in_reply_to: usize) -> Vec<Message> {
+        let Some(on_reply) = self.pending.take(in_reply_to) else {
+            eprintln!(
+                "dropping {} from {} in reply to unknown message {in_reply_to}",
+                msg.kind(),
+                msg.src.as_deref().unwrap_or("unknown node")
+            );
+            return Vec::new();
+        };
+
+        self.run_reply_callback(msg, on_reply)
+    }
+
+    fn run_reply_callback(&mut self, reply: Message, on_reply: ReplyCallback) -> Vec<Message> {
+        let in_reply_to = reply.body.in_reply_to.unwrap_or_default();
+        let ctx = self.context(reply);
+        let middlewares = self.middlewares.clone();
+        let isolation = self.handler.isolation().clone();
+
+        if let Err(error) = run_isolated(&middlewares, &isolation, &ctx, on_reply) {
+            eprintln!("failed to handle reply to message {in_reply_to}: {error}");
+        }
+
+        middlewares.outgoing(ctx.into_output_iter().collect(), &self.pending)
+    }
+
+    /// Finishes a pending `init` once all deferred handlers have completed or one of them has failed. On failure
+    /// the handlers are reset, but whatever they sent while the init was pending has already gone out.
+    fn poll_init(&mut self, now: Instant) -> Vec<Message> {
+        let status = match &self.pending_init {
+            Some(pending) => pending.tracker.status(),
+            None => return Vec::new(),
+        };
+        if let InitStatus::Pending = status {
+            return Vec::new();
+        }
+        let Some(pending) = self.pending_init.take() else {
+            return Vec::new();
+        };
+
+        let ctx = self.context(pending.msg);
+        match status {
+            InitStatus::Failed(error) => {
+                self.handler.reset();
+                self.outbox.unbind();
+                let _ = ctx.error(&error);
+            }
+            _ => {
+                if let Some(monitor) = &mut self.peers {
+                    monitor.watch(pending.membership.peers(), now);
+                }
+                self.node = Some(MaelstromServerNode::new(pending.membership));
+                let _ = ctx.reply("init_ok", &InitOkMessage);
+            }
+        }
+
+        let mut output = self
+            .middlewares
+            .outgoing(ctx.into_output_iter().collect(), &self.pending);
+
+        if self.node.is_some() {
+            while let Some(msg) = self.pre_init_queue.pop_front() {
+                output.extend(self.dispatch(msg, now));
+            }
+        }
+
+        output
+    }
+
+    /// Returns what to send instead of handling a duplicate request, or `None` if it has to be handled.
+    fn deduplicate(&self, ctx: &MessageContext, now: Instant) -> Option<Vec<Message>> {
+        let key = DeduplicationCache::key(ctx)?;
+        let mut dedup = self.dedup.as_ref()?.lock().ok()?;
+        match dedup.begin(&key, now) {
+            Lookup::New => None,
+            Lookup::Handled(replies) => Some(replies),
+            Lookup::InProgress => {
+                eprintln!(
+                    "dropping duplicate {} from {} while it's being handled",
+                    ctx.message_kind(),
+                    key.0
+                );
+                Some(Vec::new())
+            }
+        }
+    }
+
+    fn context(&self, msg: Message) -> MessageContext {
+        self.context_for(Some(msg))
+    }
+
+    fn context_for(&self, msg: Option<Message>) -> MessageContext {
+        let ctx = MessageContext::new(msg)
+            .with_outbox(self.outbox())
+            .with_message_ids(self.msg_ids.clone())
+            .with_extensions(self.extensions.clone())
+            .with_pending_requests(self.pending.clone())
+            .with_deferred_replies(self.deferred.clone());
+        match self.membership() {
+            Some(membership) => ctx.with_membership(membership.clone()),
+            None => ctx,
+        }
+    }
+
+    /// The membership of the initialized node; a pending `init` only shares its membership with the handlers' `init`.
+    fn membership(&self) -> Option<&Arc<ClusterMembership>> {
+        self.node.as_ref().map(|node| node.membership())
+    }
+
+    /// Hands the message over to the worker pool, or gives the context back if it has to be handled on the caller's thread.
+    fn offload(&self, ctx: MessageContext) -> Option<MessageContext> {
+        let Some(pool) = &self.pool else {
+            return Some(ctx);
+        };
+        if ctx.message_kind() == "init" {
+            return Some(ctx);
         }
+        let Some(handlers) = self.handler.concurrent_handlers(ctx.message_kind()) else {
+            return Some(ctx);
+        };
+
+        let outbox = self.outbox();
+        let dedup = self.dedup.clone();
+        let policy = self.handler.policy();
+        let isolation = self.handler.isolation().clone();
+        let replies = self.replies;
+        let shared_replies = self.handler.replies_shared(ctx.message_kind());
+        let middlewares = self.middlewares.clone();
+        let pending = self.pending.clone();
+        pool.execute(move || {
+            let res = run_isolated(&middlewares, &isolation, &ctx, |ctx| {
+                dispatch(policy, ctx, handlers.len(), |i| {
+                    call_shared_handler(&handlers[i], &isolation, |handler| {
+                        handle_and_respond(handler, ctx)
+                    })
+                })
+            });
+
+            let output = finish(
+                ctx,
+                res,
+                replies,
+                shared_replies,
+                &middlewares,
+                &pending,
+                dedup.as_ref(),
+            );
+            for msg in output {
+                let _ = outbox.post(msg);
+            }
+        });
 
-        ctx.into_output_iter()
+        None
     }
 
     fn handle(&mut self, ctx: &MessageContext) -> Result<(), ErrorMessage> {
@@ -56,12 +728,1560 @@ impl MaelstromService {
         }
     }
 
+    fn reset(&mut self) {
+        self.node = None;
+        self.pending_init = None;
+        self.pre_init_queue.clear();
+        self.pending.clear();
+        self.last_tick = None;
+
+        if let Some(monitor) = &mut self.peers {
+            monitor.clear();
+        }
+
+        if let Some(Ok(mut dedup)) = self.dedup.as_ref().map(|dedup| dedup.lock()) {
+            dedup.clear();
+        }
+
+        self.handler.reset();
+    }
+
+    /// Runs the handlers' `init` against a separate context whose outbox holds messages back, so nothing they sent
+    /// leaks out and the node keeps its identity if one of them fails. The handlers that already ran are reset then.
+    /// `init_ok` is sent from [`Self::poll_init`] once every handler is done.
     fn handle_init(&mut self, ctx: &MessageContext) -> Result<(), ErrorMessage> {
-        MaelstromServerNode::create(ctx).map(|node| {
-            self.node = Some(node);
-        })?;
+        let init_msg = ctx.message_content::<InitMessage>()?;
+        let membership = Arc::new(ClusterMembership::new(
+            &init_msg.node_id,
+            &init_msg.node_ids,
+        ));
+
+        let current = match (&self.node, &self.pending_init) {
+            (Some(node), _) => Some(node.membership()),
+            (None, Some(pending)) => Some(&pending.membership),
+            (None, None) => None,
+        };
+        if let Some(current) = current {
+            match self.reinit {
+                ReinitPolicy::Reject if self.node.is_none() => {
+                    return Err(ErrorMessage::new(
+                        ErrorKind::TemporarilyUnavailable,
+                        "node initialization in progress",
+                    ))
+                }
+                ReinitPolicy::Reject => {
+                    return Err(ErrorMessage::new(
+                        ErrorKind::PreconditionFailed,
+                        "node already initialized",
+                    ))
+                }
+                ReinitPolicy::IgnoreIdentical if *current != membership => {
+                    return Err(ErrorMessage::new(
+                        ErrorKind::PreconditionFailed,
+                        "node already initialized with a different membership",
+                    ))
+                }
+                ReinitPolicy::IgnoreIdentical if self.node.is_none() => {
+                    return Err(ErrorMessage::new(
+                        ErrorKind::TemporarilyUnavailable,
+                        "node initialization in progress",
+                    ))
+                }
+                ReinitPolicy::IgnoreIdentical => return ctx.reply("init_ok", &InitOkMessage),
+                ReinitPolicy::Reset => self.reset(),
+            }
+        }
+
+        let tracker = InitTracker::default();
+        let outbox = self.outbox.staged(membership.node_id());
+
+        let init_ctx = MessageContext::new(ctx.message().cloned())
+            .with_outbox(outbox.clone())
+            .with_message_ids(self.msg_ids.clone())
+            .with_extensions(self.extensions.clone())
+            .with_pending_requests(self.pending.clone())
+            .with_deferred_replies(self.deferred.clone())
+            .with_membership(membership.clone())
+            .with_init_tracker(tracker.clone());
+
+        let res = self
+            .handler
+            .handle_init(&init_msg, &init_ctx)
+            .and_then(|()| match tracker.status() {
+                InitStatus::Failed(error) => Err(error),
+                InitStatus::Ready | InitStatus::Pending => Ok(()),
+            });
+
+        if let Err(error) = res {
+            outbox.discard();
+            for msg in init_ctx.into_output_iter() {
+                if let Some(msg_id) = msg.body.msg_id {
+                    self.pending.take(msg_id);
+                }
+            }
+            self.handler.reset();
+            return Err(error);
+        }
+
+        self.outbox.bind(membership.node_id());
+        outbox.release()?;
+
+        ctx.extend_output(init_ctx.into_output_iter());
+        ctx.set_reply_deferred(); // `init_ok` is sent by `poll_init`
+        self.pending_init = ctx.message().cloned().map(|msg| PendingInit {
+            msg,
+            membership,
+            tracker,
+        });
+
+        Ok(())
+    }
+}
+
+/// Runs the middlewares around `handle`, catching a panic in any of them like one in a handler. Handlers catch
+/// their own panics, but reply callbacks don't, so theirs are caught here too.
+fn run_isolated(
+    middlewares: &MiddlewareChain,
+    isolation: &PanicIsolation,
+    ctx: &MessageContext,
+    handle: impl FnOnce(&MessageContext) -> Result<(), ErrorMessage>,
+) -> Result<(), ErrorMessage> {
+    isolation
+        .catch(|| middlewares.run(ctx, handle))
+        .unwrap_or_else(|err| {
+            if isolation.policy() == PanicPolicy::Isolate {
+                middlewares.clear_poison();
+            }
+            Err(err)
+        })
+}
+
+/// Turns the result of handling a message into the messages to send: replies with the error if there was one,
+/// checks the replies against `replies`, lets the middlewares rewrite the output and remembers it for deduplication.
+fn finish(
+    ctx: MessageContext,
+    mut res: Result<(), ErrorMessage>,
+    replies: ReplyPolicy,
+    shared_replies: bool,
+    middlewares: &MiddlewareChain,
+    pending: &PendingRequests,
+    dedup: Option<&Arc<Mutex<DeduplicationCache>>>,
+) -> Vec<Message> {
+    let dedup_key = DeduplicationCache::key(&ctx);
+    let deferred = ctx.is_reply_deferred();
+
+    if let Err(error) = &res {
+        let _ = ctx.error(error);
+    }
+
+    if let Some(violation) = check_replies(&ctx, replies, shared_replies) {
+        eprintln!("{violation}");
+        if replies == ReplyPolicy::Strict {
+            ctx.discard_replies();
+            let _ = ctx.error(&violation);
+            res = Err(violation);
+        }
+    }
+
+    let output = middlewares.outgoing(ctx.into_output_iter().collect(), pending);
+
+    // Deferred replies stay in progress until they're sent, see `enable_deduplication`
+    if let (Some(Ok(mut dedup)), Some(key)) = (dedup.map(|dedup| dedup.lock()), dedup_key) {
+        match res {
+            Ok(()) if deferred => {}
+            Ok(()) => dedup.insert(key, &output, Instant::now()),
+            Err(_) => dedup.abandon(&key),
+        }
+    }
+
+    output
+}
+
+/// Reports a client request that didn't get exactly one reply, or none at all if several handlers share replying
+/// to it, unless its reply was deferred.
+fn check_replies(ctx: &MessageContext, policy: ReplyPolicy, shared: bool) -> Option<ErrorMessage> {
+    if policy == ReplyPolicy::Unchecked || ctx.is_reply_deferred() || ctx.message_id().is_none() {
+        return None;
+    }
+    let src = ctx.message_src();
+    if src.is_some_and(|src| ctx.node_ids().iter().any(|node| node == src)) {
+        return None;
+    }
+
+    match ctx.reply_count() {
+        1 => None,
+        0 if shared => Some(ErrorMessage::new(
+            ErrorKind::Crash,
+            &format!("request {} got no reply", ctx.message_kind()),
+        )),
+        _ if shared => None,
+        count => Some(ErrorMessage::new(
+            ErrorKind::Crash,
+            &format!(
+                "request {} got {count} replies instead of one",
+                ctx.message_kind()
+            ),
+        )),
+    }
+}
+
+impl Default for MaelstromService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{de::StrRead, Deserializer};
+
+    use super::*;
+    use crate::protocol::{DeferredInit, Response};
+
+    #[derive(Default)]
+    struct PingHandler;
+
+    impl MessageHandler for PingHandler {
+        fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+            ["ping"].into_iter()
+        }
+
+        fn handle(&mut self, _ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+            Response::new("pong", &()).map(Some)
+        }
+    }
+
+    fn input(service: &mut MaelstromService, line: &str) -> Vec<Message> {
+        let mut de = Deserializer::new(StrRead::new(line));
+        service.input(&mut de).collect()
+    }
+
+    #[derive(Default)]
+    struct CounterHandler {
+        value: usize,
+    }
+
+    impl MessageHandler for CounterHandler {
+        fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+            ["add"].into_iter()
+        }
+
+        fn reset(&mut self) {
+            self.value = 0;
+        }
+
+        fn handle(&mut self, _ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+            self.value += 1;
+            Response::new("add_ok", &serde_json::json!({ "value": self.value })).map(Some)
+        }
+    }
+
+    #[test]
+    fn test_deduplication() {
+        let mut service = MaelstromService::new();
+        service.register_handler::<CounterHandler>();
+        service.enable_deduplication(DeduplicationConfig::default());
+
+        let add = |msg_id: usize| {
+            format!(r#"{{"src":"c1","dest":"n1","body":{{"type":"add","msg_id":{msg_id}}}}}"#)
+        };
+
+        let first = input(&mut service, &add(1));
+        let duplicate = input(&mut service, &add(1));
+        let second = input(&mut service, &add(2));
+
+        assert_eq!(first.len(), 1);
+        assert_eq!(duplicate, first);
+        assert_eq!(
+            second[0].body.content.data.get("value"),
+            Some(&serde_json::Value::from(2))
+        );
+    }
+
+    #[test]
+    fn test_deduplication_deferred_replies() {
+        let mut service = MaelstromService::new();
+        service.register_handler::<QueryHandler>();
+        service.enable_deduplication(DeduplicationConfig::default());
+
+        let query = r#"{"src":"c1","dest":"n1","body":{"type":"query","msg_id":7}}"#;
+
+        let read = input(&mut service, query);
+        assert_eq!(read[0].kind(), "read");
+
+        // Still waiting for the reply, so the retry is dropped instead of handled again
+        assert!(input(&mut service, query).is_empty());
+        assert_eq!(service.pending_requests().len(), 1);
+
+        let read_ok = format!(
+            r#"{{"src":"lin-kv","dest":"n1","body":{{"type":"read_ok","msg_id":1,"in_reply_to":{},"value":42}}}}"#,
+            read[0].body.msg_id.unwrap()
+        );
+        input(&mut service, &read_ok);
+        let answer = service.drain_outbox().next().unwrap();
+        assert_eq!(answer.kind(), "query_ok");
+
+        // The deferred reply is replayed without handling the query again
+        assert_eq!(input(&mut service, query), vec![answer]);
+        assert!(service.pending_requests().is_empty());
+    }
+
+    const INIT: &str = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#;
+    const PING: &str = r#"{"src":"c1","dest":"n1","body":{"type":"ping","msg_id":1}}"#;
+
+    #[test]
+    fn test_pre_init_reject() {
+        let mut service = MaelstromService::new();
+        service.register_handler::<PingHandler>();
+        service.set_pre_init_policy(PreInitPolicy::Reject);
+
+        let replies = input(&mut service, PING);
+
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].kind(), "error");
+        assert_eq!(
+            replies[0].body.content.data.get("code"),
+            Some(&serde_json::Value::from(usize::from(
+                ErrorKind::TemporarilyUnavailable
+            )))
+        );
+
+        input(&mut service, INIT);
+
+        assert_eq!(input(&mut service, PING)[0].kind(), "pong");
+    }
+
+    #[test]
+    fn test_pre_init_buffer() {
+        let mut service = MaelstromService::new();
+        service.register_handler::<PingHandler>();
+        service.set_pre_init_policy(PreInitPolicy::Buffer { capacity: 1 });
+
+        assert!(input(&mut service, PING).is_empty());
+        assert_eq!(input(&mut service, PING)[0].kind(), "error");
+
+        let kinds = input(&mut service, INIT)
+            .into_iter()
+            .map(|msg| msg.body.content.kind)
+            .collect::<Vec<_>>();
+
+        assert_eq!(kinds, vec!["init_ok", "pong"]);
+    }
+
+    #[derive(Default)]
+    struct FailingInitHandler;
+
+    impl MessageHandler for FailingInitHandler {
+        fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+            std::iter::empty()
+        }
+
+        fn init(
+            &mut self,
+            _node_id: &str,
+            _node_ids: &[String],
+            ctx: &MessageContext,
+        ) -> Result<(), ErrorMessage> {
+            ctx.outbox()?.send("lin-kv", "read", &())?;
+            ctx.reply("leaked", &())?;
+            Err(ErrorMessage::new(ErrorKind::Crash, "hello there"))
+        }
+
+        fn handle(&mut self, _ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_init_failure() {
+        let mut service = MaelstromService::new();
+        service.register_handler::<PingHandler>();
+        service.register_handler::<FailingInitHandler>();
+
+        let replies = input(&mut service, INIT);
+
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].kind(), "error");
+        assert!(!service.is_initialized());
+        assert_eq!(service.drain_outbox().count(), 0);
+        assert!(service.pending_requests().is_empty());
+
+        // The outbox isn't bound to the node id of the failed init
+        service.outbox().send("n2", "gossip", &()).unwrap();
+        assert_eq!(service.drain_outbox().next().unwrap().src, None);
+    }
+
+    #[derive(Default)]
+    struct DeferredInitHandler;
+
+    impl MessageHandler for DeferredInitHandler {
+        fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+            std::iter::empty()
+        }
+
+        fn init(
+            &mut self,
+            _node_id: &str,
+            _node_ids: &[String],
+            ctx: &MessageContext,
+        ) -> Result<(), ErrorMessage> {
+            let init = ctx.defer_init()?;
+            ctx.outbox()?.rpc("lin-kv", "read", &(), move |_| {
+                init.complete();
+                Ok(())
+            })
+        }
+
+        fn handle(&mut self, _ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_deferred_init() {
+        let mut service = MaelstromService::new();
+        service.register_handler::<PingHandler>();
+        service.register_handler::<DeferredInitHandler>();
+        service.set_pre_init_policy(PreInitPolicy::Buffer { capacity: 1 });
+
+        assert!(input(&mut service, INIT).is_empty());
+        assert!(!service.is_initialized());
+
+        let read = service.drain_outbox().next().unwrap();
+        assert_eq!(read.src, Some("n1".to_string()));
+        assert_eq!(read.dest, Some("lin-kv".to_string()));
+
+        assert!(input(&mut service, PING).is_empty());
+
+        let read_ok = format!(
+            r#"{{"src":"lin-kv","dest":"n1","body":{{"type":"read_ok","msg_id":1,"in_reply_to":{}}}}}"#,
+            read.body.msg_id.unwrap()
+        );
+        let kinds = input(&mut service, &read_ok)
+            .into_iter()
+            .map(|msg| msg.body.content.kind)
+            .collect::<Vec<_>>();
+
+        assert_eq!(kinds, vec!["init_ok", "pong"]);
+        assert!(service.is_initialized());
+    }
+
+    struct HandOffInitHandler {
+        deferred: std::sync::mpsc::Sender<DeferredInit>,
+    }
+
+    impl MessageHandler for HandOffInitHandler {
+        fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+            std::iter::empty()
+        }
+
+        fn init(
+            &mut self,
+            _node_id: &str,
+            _node_ids: &[String],
+            ctx: &MessageContext,
+        ) -> Result<(), ErrorMessage> {
+            let _ = self.deferred.send(ctx.defer_init()?);
+            Ok(())
+        }
+
+        fn handle(&mut self, _ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_deferred_init_completed_elsewhere() {
+        let (deferred, handed_off) = std::sync::mpsc::channel();
+
+        let mut service = MaelstromService::new();
+        service.register_handler_instance(HandOffInitHandler { deferred });
+
+        assert!(input(&mut service, INIT).is_empty());
+        assert_eq!(service.tick(Instant::now()).count(), 0);
+
+        let init = handed_off.recv().unwrap();
+        std::thread::spawn(move || init.complete()).join().unwrap();
+
+        let replies = service.tick(Instant::now()).collect::<Vec<_>>();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].kind(), "init_ok");
+        assert!(service.is_initialized());
+    }
+
+    #[test]
+    fn test_deferred_init_failed() {
+        let (deferred, handed_off) = std::sync::mpsc::channel();
+
+        let mut service = MaelstromService::new();
+        service.register_handler_instance(HandOffInitHandler { deferred });
+        service.on("status", |ctx, _: serde_json::Value| {
+            Ok(serde_json::json!({ "initialized": ctx.is_initialized() }))
+        });
+
+        assert!(input(&mut service, INIT).is_empty());
+
+        // Delivered before init, and the pending init doesn't count yet
+        let status = r#"{"src":"c1","dest":"n1","body":{"type":"status","msg_id":2}}"#;
+        let replies = input(&mut service, status);
+        assert_eq!(
+            replies[0].body.content.data.get("initialized"),
+            Some(&serde_json::Value::from(false))
+        );
+
+        handed_off
+            .recv()
+            .unwrap()
+            .fail(ErrorMessage::new(ErrorKind::Abort, "no storage"));
+        let replies = service.tick(Instant::now()).collect::<Vec<_>>();
+        assert_eq!(replies[0].kind(), "error");
+        assert!(!service.is_initialized());
+
+        service.outbox().send("n2", "gossip", &()).unwrap();
+        assert_eq!(service.drain_outbox().next().unwrap().src, None);
+    }
+
+    fn init(node_ids: &str) -> String {
+        format!(
+            r#"{{"src":"c0","dest":"n1","body":{{"type":"init","msg_id":1,"node_id":"n1","node_ids":{node_ids}}}}}"#
+        )
+    }
+
+    #[test]
+    fn test_reinit_reject() {
+        let mut service = MaelstromService::new();
+
+        assert_eq!(input(&mut service, INIT)[0].kind(), "init_ok");
+
+        let replies = input(&mut service, INIT);
+        assert_eq!(replies[0].kind(), "error");
+        assert_eq!(
+            replies[0].body.content.data.get("code"),
+            Some(&serde_json::Value::from(usize::from(
+                ErrorKind::PreconditionFailed
+            )))
+        );
+    }
+
+    #[test]
+    fn test_reinit_ignore_identical() {
+        let mut service = MaelstromService::new();
+        service.set_reinit_policy(ReinitPolicy::IgnoreIdentical);
+
+        assert_eq!(input(&mut service, &init(r#"["n1"]"#))[0].kind(), "init_ok");
+        assert_eq!(input(&mut service, &init(r#"["n1"]"#))[0].kind(), "init_ok");
+        assert_eq!(
+            input(&mut service, &init(r#"["n1","n2"]"#))[0].kind(),
+            "error"
+        );
+    }
+
+    #[test]
+    fn test_reinit_reject_during_deferred_init() {
+        let mut service = MaelstromService::new();
+        service.register_handler::<DeferredInitHandler>();
+
+        assert!(input(&mut service, INIT).is_empty());
+
+        // A client retrying the init while it's pending is told to wait, not that the node is initialized
+        let replies = input(&mut service, INIT);
+        assert_eq!(replies[0].kind(), "error");
+        assert_eq!(
+            replies[0].body.content.data.get("code"),
+            Some(&serde_json::Value::from(usize::from(
+                ErrorKind::TemporarilyUnavailable
+            )))
+        );
+    }
+
+    #[test]
+    fn test_reinit_reset() {
+        let add = |service: &mut MaelstromService| {
+            let add = r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":1}}"#;
+            input(service, add)[0]
+                .body
+                .content
+                .data
+                .get("value")
+                .cloned()
+        };
+
+        let mut service = MaelstromService::new();
+        service.register_handler::<CounterHandler>();
+        service.set_reinit_policy(ReinitPolicy::Reset);
+
+        input(&mut service, &init(r#"["n1"]"#));
+        add(&mut service);
+        assert_eq!(add(&mut service), Some(2.into()));
+
+        service.outbox().rpc("n2", "read", &(), |_| Ok(())).unwrap();
+        assert_eq!(service.pending_requests().len(), 1);
+
+        assert_eq!(
+            input(&mut service, &init(r#"["n1","n2"]"#))[0].kind(),
+            "init_ok"
+        );
+        assert_eq!(add(&mut service), Some(1.into()));
+        assert!(service.pending_requests().is_empty());
+    }
+
+    #[derive(Default)]
+    struct LifecycleHandler;
+
+    impl MessageHandler for LifecycleHandler {
+        fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+            ["gossip"].into_iter()
+        }
+
+        fn on_tick(&mut self, ctx: &MessageContext) -> Result<(), ErrorMessage> {
+            ctx.broadcast("gossip", &())
+        }
+
+        fn on_peer_unreachable(
+            &mut self,
+            peer: &str,
+            ctx: &MessageContext,
+        ) -> Result<(), ErrorMessage> {
+            ctx.outbox()?.send(peer, "probe", &())
+        }
+
+        fn on_shutdown(&mut self, ctx: &MessageContext) -> Result<(), ErrorMessage> {
+            ctx.broadcast("flush", &())
+        }
+
+        fn handle(&mut self, _ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_reject_input() {
+        let mut service = MaelstromService::new();
+        input(&mut service, INIT);
+
+        let mut line =
+            br#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":3,"echo":""#.to_vec();
+        line.extend_from_slice(b"\xff\"}}");
+        let replies = service
+            .reject_input(&InputError::InvalidUtf8(line))
+            .collect::<Vec<_>>();
+
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].kind(), "error");
+        assert_eq!(replies[0].src.as_deref(), Some("n1"));
+        assert_eq!(replies[0].dest.as_deref(), Some("c1"));
+        assert_eq!(replies[0].body.in_reply_to, Some(3));
+        assert_eq!(
+            replies[0].body.content.data.get("code"),
+            Some(&serde_json::Value::from(usize::from(
+                ErrorKind::MalformedRequest
+            )))
+        );
+
+        let anonymous = InputError::InvalidUtf8(b"\xff\xfe".to_vec());
+        assert_eq!(service.reject_input(&anonymous).count(), 0);
+    }
+
+    #[derive(Default)]
+    struct PanickingHandler;
+
+    impl MessageHandler for PanickingHandler {
+        fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+            ["boom", "fine"].into_iter()
+        }
+
+        fn handle(&mut self, ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+            match ctx.message_kind() {
+                "boom" => panic!("kaboom"),
+                _ => Response::new("fine_ok", &()).map(Some),
+            }
+        }
+    }
+
+    #[test]
+    fn test_panic_isolation() {
+        let request = |service: &mut MaelstromService, kind: &str| {
+            let line =
+                format!(r#"{{"src":"c1","dest":"n1","body":{{"type":"{kind}","msg_id":1}}}}"#);
+            let reply = input(service, &line).remove(0);
+            let text = reply.body.content.data.get("text").cloned();
+            (reply.body.content.kind, text)
+        };
+
+        for concurrent in [false, true] {
+            for policy in [PanicPolicy::Isolate, PanicPolicy::Poison] {
+                let mut service = MaelstromService::new();
+                service.set_panic_policy(policy);
+                if concurrent {
+                    service.register_concurrent_handler::<PanickingHandler>();
+                } else {
+                    service.register_handler::<PanickingHandler>();
+                }
+
+                assert_eq!(
+                    request(&mut service, "boom"),
+                    ("error".to_string(), Some("handler panicked: kaboom".into()))
+                );
+                assert_eq!(service.isolated_panics(), 1);
+
+                let fine = request(&mut service, "fine");
+                match policy {
+                    PanicPolicy::Isolate => assert_eq!(fine, ("fine_ok".to_string(), None)),
+                    PanicPolicy::Poison => assert_eq!(
+                        fine,
+                        ("error".to_string(), Some("message handler poisoned".into()))
+                    ),
+                }
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct ExplodingMiddleware;
+
+    impl Middleware for ExplodingMiddleware {
+        fn before(&mut self, ctx: &MessageContext) -> Result<(), ErrorMessage> {
+            if ctx
+                .message_content::<serde_json::Value>()?
+                .get("explode")
+                .is_some()
+            {
+                panic!("middleware exploded");
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reply_callback_panic() {
+        let mut service = MaelstromService::new();
+        service.set_rpc_timeout(Duration::from_secs(1));
+        input(&mut service, INIT);
+
+        let outbox = service.outbox();
+        outbox
+            .rpc("lin-kv", "read", &(), |_| panic!("bad reply"))
+            .unwrap();
+        outbox
+            .rpc("lin-kv", "read", &(), |_| panic!("timed out"))
+            .unwrap();
+        let read = service.drain_outbox().next().unwrap();
+
+        let read_ok = format!(
+            r#"{{"src":"lin-kv","dest":"n1","body":{{"type":"read_ok","msg_id":1,"in_reply_to":{}}}}}"#,
+            read.body.msg_id.unwrap()
+        );
+        assert!(service.input_line(&read_ok).next().is_none());
+        assert_eq!(service.isolated_panics(), 1);
+
+        let later = Instant::now() + Duration::from_secs(2);
+        assert_eq!(service.tick(later).count(), 0);
+        assert_eq!(service.isolated_panics(), 2);
+        assert!(service.pending_requests().is_empty());
+    }
+
+    #[test]
+    fn test_middleware_panic() {
+        for concurrent in [false, true] {
+            let mut service = MaelstromService::new();
+            service.register_middleware::<ExplodingMiddleware>();
+            if concurrent {
+                service.register_concurrent_handler::<PingHandler>();
+                service.enable_worker_pool(1);
+            } else {
+                service.register_handler::<PingHandler>();
+            }
+
+            let mut send = |line: &str| {
+                let mut replies = input(&mut service, line);
+                service.join_worker_pool();
+                if concurrent {
+                    service.enable_worker_pool(1);
+                }
+                replies.extend(service.drain_outbox());
+                replies
+            };
+
+            let replies = send(
+                r#"{"src":"c1","dest":"n1","body":{"type":"ping","msg_id":1,"explode":true}}"#,
+            );
+            assert_eq!(replies.len(), 1);
+            assert_eq!(replies[0].kind(), "error");
+            assert_eq!(
+                replies[0].body.content.data.get("text"),
+                Some(&serde_json::Value::from(
+                    "handler panicked: middleware exploded"
+                ))
+            );
+
+            // The middleware is used again for later messages
+            let replies = send(r#"{"src":"c1","dest":"n1","body":{"type":"ping","msg_id":2}}"#);
+            assert_eq!(replies[0].kind(), "pong");
+
+            assert_eq!(service.isolated_panics(), 1);
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_dead_letters() {
+        let mut service = MaelstromService::new();
+        let log = SharedBuffer::default();
+        service.set_dead_letter_output(log.clone());
+
+        // Nobody to reply as before init, so it's only logged
+        let salvageable = r#"{"src":"c1","dest":"n1","body":{"msg_id":4}}"#;
+        assert_eq!(service.input_line(salvageable).count(), 0);
+
+        input(&mut service, INIT);
+
+        // Nothing to reply to, so it's only logged
+        let unroutable = r#"{"body":{"type":"echo","msg_id":1}"#;
+        assert_eq!(service.input_line(unroutable).count(), 0);
+
+        let replies = service.input_line(salvageable).collect::<Vec<_>>();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].kind(), "error");
+        assert_eq!(replies[0].src.as_deref(), Some("n1"));
+        assert_eq!(replies[0].dest.as_deref(), Some("c1"));
+        assert_eq!(replies[0].body.in_reply_to, Some(4));
+
+        assert_eq!(input(&mut service, "[1, 2").len(), 0);
+
+        assert_eq!(service.dead_letters(), 4);
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].ends_with(salvageable));
+        assert!(lines[1].ends_with(unroutable));
+        assert!(lines[2].ends_with(salvageable));
+        assert!(lines[3].ends_with("<unavailable>"));
+    }
+
+    #[test]
+    fn test_lifecycle_hooks() {
+        let mut service = MaelstromService::new();
+        service.register_handler::<LifecycleHandler>();
+        service.set_tick_interval(Duration::from_millis(100));
+        service.set_peer_timeout(Duration::from_secs(1));
+
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        assert_eq!(service.tick(start).count(), 0); // Not initialized yet
+
+        service
+            .input_line_at(&init(r#"["n1","n2","n3"]"#), start)
+            .for_each(drop);
+
+        let kinds = |msgs: Vec<Message>| {
+            msgs.into_iter()
+                .map(|msg| format!("{}:{}", msg.kind(), msg.dest.as_deref().unwrap_or_default()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            kinds(service.tick(start).collect()),
+            vec!["gossip:n2", "gossip:n3"]
+        );
+        assert_eq!(service.tick(at(50)).count(), 0);
+
+        assert_eq!(service.tick(at(1500)).count(), 2);
+        assert_eq!(
+            kinds(service.drain_outbox().collect()),
+            vec!["probe:n2", "probe:n3"]
+        );
+
+        // Unreachable peers are reported again only after they've been heard from and timed out once more
+        service.tick(at(1600)).for_each(drop);
+        assert_eq!(service.drain_outbox().count(), 0);
+
+        let gossip = r#"{"src":"n3","dest":"n1","body":{"type":"gossip"}}"#;
+        service.input_line_at(gossip, at(1700)).for_each(drop);
+
+        service.tick(at(2500)).for_each(drop);
+        assert_eq!(service.drain_outbox().count(), 0);
+
+        service.tick(at(2800)).for_each(drop);
+        assert_eq!(kinds(service.drain_outbox().collect()), vec!["probe:n3"]);
+
+        let report = service.shutdown(Duration::from_secs(1));
+        assert!(report.is_clean());
+        assert_eq!(
+            kinds(service.drain_outbox().collect()),
+            vec!["flush:n2", "flush:n3"]
+        );
+    }
+
+    #[test]
+    fn test_shutdown_reports_lost_work() {
+        let mut service = MaelstromService::new();
+        service.register_handler::<QueryHandler>();
+        service.set_pre_init_policy(PreInitPolicy::Buffer { capacity: 1 });
+
+        input(
+            &mut service,
+            r#"{"src":"c1","dest":"n1","body":{"type":"query","msg_id":1}}"#,
+        );
+        input(&mut service, INIT);
+        input(
+            &mut service,
+            r#"{"src":"c2","dest":"n1","body":{"type":"query","msg_id":1}}"#,
+        );
+
+        let report = service.shutdown(Duration::from_secs(1));
+        assert!(!report.is_clean());
+        assert_eq!(report.unanswered_requests, 2);
+        assert_eq!(report.unsent_replies, 2);
+        assert_eq!(report.unhandled_messages, 0);
+        assert!(report.flushed);
+    }
+
+    #[derive(Default)]
+    struct AuthMiddleware;
+
+    impl Middleware for AuthMiddleware {
+        fn before(&mut self, ctx: &MessageContext) -> Result<(), ErrorMessage> {
+            match ctx.message_src() {
+                Some("c1") => Ok(()),
+                _ => Err(ErrorMessage::new(ErrorKind::Abort, "unauthorized")),
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct TaggingMiddleware;
+
+    impl Middleware for TaggingMiddleware {
+        fn after(
+            &mut self,
+            _ctx: &MessageContext,
+            result: Result<(), ErrorMessage>,
+        ) -> Result<(), ErrorMessage> {
+            result.map_err(|err| ErrorMessage::new(ErrorKind::Crash, err.text()))
+        }
+
+        fn outgoing(&mut self, mut msg: Message) -> Option<Message> {
+            msg.body
+                .content
+                .data
+                .insert("tagged".to_string(), serde_json::Value::Bool(true));
+            Some(msg)
+        }
+    }
+
+    #[test]
+    fn test_middleware() {
+        let mut service = MaelstromService::new();
+        service.register_handler::<PingHandler>();
+        service.register_middleware::<TaggingMiddleware>();
+        service.register_middleware::<AuthMiddleware>();
+
+        let replies = input(&mut service, PING);
+        assert_eq!(replies[0].kind(), "pong");
+        assert_eq!(
+            replies[0].body.content.data.get("tagged"),
+            Some(&serde_json::Value::Bool(true))
+        );
+
+        let replies = input(
+            &mut service,
+            r#"{"src":"c2","dest":"n1","body":{"type":"ping","msg_id":1}}"#,
+        );
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].kind(), "error");
+        assert_eq!(
+            replies[0].body.content.data.get("code"),
+            Some(&serde_json::Value::from(usize::from(ErrorKind::Crash)))
+        );
+        assert_eq!(
+            replies[0].body.content.data.get("tagged"),
+            Some(&serde_json::Value::Bool(true))
+        );
+    }
+
+    struct Topology(Vec<String>);
+
+    #[derive(Default)]
+    struct TopologyHandler;
+
+    impl MessageHandler for TopologyHandler {
+        fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+            ["topology"].into_iter()
+        }
+
+        fn handle(&mut self, ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+            let neighbours = ctx.message_content::<serde_json::Value>()?["neighbours"]
+                .as_array()
+                .map(|nodes| nodes.iter().map(|node| node.to_string()).collect())
+                .unwrap_or_default();
+            ctx.extensions()
+                .with_mut(|topology: &mut Topology| topology.0 = neighbours)?;
+            Response::new("topology_ok", &()).map(Some)
+        }
+    }
+
+    #[derive(Default)]
+    struct NeighboursHandler;
+
+    impl MessageHandler for NeighboursHandler {
+        fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+            ["neighbours"].into_iter()
+        }
+
+        fn handle(&mut self, ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+            let neighbours = ctx
+                .extensions()
+                .with(|topology: &Topology| topology.0.len())?;
+            Response::new("neighbours_ok", &serde_json::json!({ "count": neighbours })).map(Some)
+        }
+    }
+
+    #[test]
+    fn test_extensions() {
+        let mut service = MaelstromService::new();
+        service.register_handler::<TopologyHandler>();
+        service.register_handler::<NeighboursHandler>();
+        service.extensions().insert(Topology(Vec::new()));
+
+        input(
+            &mut service,
+            r#"{"src":"c1","dest":"n1","body":{"type":"topology","msg_id":1,"neighbours":["n2","n3","n4"]}}"#,
+        );
+
+        let replies = input(
+            &mut service,
+            r#"{"src":"c1","dest":"n1","body":{"type":"neighbours","msg_id":2}}"#,
+        );
+        assert_eq!(
+            replies[0].body.content.data.get("count"),
+            Some(&serde_json::Value::from(3))
+        );
+    }
+
+    struct ConstantHandler {
+        kind: &'static str,
+        value: usize,
+    }
+
+    impl Default for ConstantHandler {
+        fn default() -> Self {
+            Self {
+                kind: "constant",
+                value: 0,
+            }
+        }
+    }
+
+    impl MessageHandler for ConstantHandler {
+        fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+            [self.kind].into_iter()
+        }
+
+        fn handle(&mut self, _ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+            Response::new(
+                &format!("{}_ok", self.kind),
+                &serde_json::json!({ "value": self.value }),
+            )
+            .map(Some)
+        }
+    }
+
+    #[test]
+    fn test_handler_instances() {
+        let mut service = MaelstromService::new();
+        service.register_handler_instance(ConstantHandler {
+            kind: "one",
+            value: 1,
+        });
+        service.register_handler_instance(ConstantHandler {
+            kind: "two",
+            value: 2,
+        });
+
+        for (kind, value) in [("one", 1), ("two", 2)] {
+            let replies = input(
+                &mut service,
+                &format!(r#"{{"src":"c1","dest":"n1","body":{{"type":"{kind}","msg_id":1}}}}"#),
+            );
+            assert_eq!(replies[0].kind(), format!("{kind}_ok"));
+            assert_eq!(
+                replies[0].body.content.data.get("value"),
+                Some(&serde_json::Value::from(value))
+            );
+        }
+    }
+
+    #[test]
+    fn test_closure_handlers() {
+        #[derive(Deserialize)]
+        struct Add {
+            delta: usize,
+        }
+
+        let mut service = MaelstromService::new();
+        let mut total = 0;
+        service.on("add", move |_, req: Add| {
+            total += req.delta;
+            Ok(serde_json::json!({ "total": total }))
+        });
+
+        for (msg_id, total) in [(1, 2), (2, 4)] {
+            let replies = input(
+                &mut service,
+                &format!(
+                    r#"{{"src":"c1","dest":"n1","body":{{"type":"add","msg_id":{msg_id},"delta":2}}}}"#
+                ),
+            );
+            assert_eq!(replies[0].kind(), "add_ok");
+            assert_eq!(replies[0].body.in_reply_to, Some(msg_id));
+            assert_eq!(
+                replies[0].body.content.data.get("total"),
+                Some(&serde_json::Value::from(total))
+            );
+        }
+
+        let malformed = input(
+            &mut service,
+            r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":3}}"#,
+        );
+        assert_eq!(malformed[0].kind(), "error");
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Double {
+        value: i64,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct DoubleOk {
+        value: i64,
+    }
+
+    impl Rpc for Double {
+        const KIND: &'static str = "double";
+        const RESPONSE_KIND: &'static str = "double_ok";
+
+        type Response = DoubleOk;
+    }
+
+    #[test]
+    fn test_typed_rpc() {
+        let mut server = MaelstromService::new();
+        server.serve(|_, req: Double| {
+            Ok(DoubleOk {
+                value: req.value * 2,
+            })
+        });
+
+        let replies = input(
+            &mut server,
+            r#"{"src":"n1","dest":"n2","body":{"type":"double","msg_id":5,"value":21}}"#,
+        );
+        assert_eq!(replies[0].kind(), "double_ok");
+        assert_eq!(
+            replies[0].body.content.data.get("value"),
+            Some(&serde_json::Value::from(42))
+        );
+
+        let mut client = MaelstromService::new();
+        client.on("relay", |ctx, req: Double| {
+            let client = ctx.message_src().unwrap_or_default().to_owned();
+            ctx.call("n2", &req, move |reply, res| {
+                let answer = match res {
+                    Ok(res) => serde_json::json!({ "value": res.value }),
+                    Err(err) => serde_json::json!({ "code": err.code() }),
+                };
+                reply.outbox()?.send(&client, "answer", &answer)
+            })?;
+            Ok(())
+        });
+
+        let mut answers = Vec::new();
+        for reply in [
+            r#""type":"double_ok","value":42"#,
+            r#""type":"error","code":11,"text":"busy""#,
+        ] {
+            let call = input(
+                &mut client,
+                r#"{"src":"c1","dest":"n1","body":{"type":"relay","msg_id":1,"value":21}}"#,
+            );
+            let msg_id = call[0].body.msg_id.unwrap();
+            assert_eq!(call[0].kind(), "double");
+
+            input(
+                &mut client,
+                &format!(
+                    r#"{{"src":"n2","dest":"n1","body":{{"msg_id":1,"in_reply_to":{msg_id},{reply}}}}}"#
+                ),
+            );
+            answers.push(client.drain_outbox().next().unwrap().body.content.data);
+        }
+
+        assert_eq!(answers[0].get("value"), Some(&serde_json::Value::from(42)));
+        assert_eq!(answers[1].get("code"), Some(&serde_json::Value::from(11)));
+    }
+
+    #[derive(Default)]
+    struct SloppyHandler;
+
+    impl MessageHandler for SloppyHandler {
+        fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+            ["silent", "twice"].into_iter()
+        }
+
+        fn handle(&mut self, ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+            match ctx.message_kind() {
+                "twice" => {
+                    ctx.reply("twice_ok", &())?;
+                    Response::new("twice_ok", &()).map(Some)
+                }
+                _ => Ok(None),
+            }
+        }
+    }
+
+    #[test]
+    fn test_reply_check() {
+        let mut service = MaelstromService::new();
+        service.register_handler::<SloppyHandler>();
+
+        // Strict is the default in debug builds
+        for kind in ["silent", "twice"] {
+            let replies = input(
+                &mut service,
+                &format!(r#"{{"src":"c1","dest":"n1","body":{{"type":"{kind}","msg_id":1}}}}"#),
+            );
+            assert_eq!(replies.len(), 1);
+            assert_eq!(replies[0].kind(), "error");
+            assert_eq!(
+                replies[0].body.content.data.get("code"),
+                Some(&serde_json::Value::from(usize::from(ErrorKind::Crash)))
+            );
+        }
+
+        service.set_reply_policy(ReplyPolicy::Unchecked);
+        let replies = input(
+            &mut service,
+            r#"{"src":"c1","dest":"n1","body":{"type":"twice","msg_id":2}}"#,
+        );
+        assert_eq!(replies.len(), 2);
+    }
+
+    #[test]
+    fn test_reply_check_shared() {
+        let mut service = MaelstromService::new();
+        service.set_dispatch_policy(DispatchPolicy::FanOut);
+        service.set_reply_policy(ReplyPolicy::Strict);
+        service.register_handler::<PingHandler>();
+        service.register_handler::<PingHandler>();
+        service.register_handler::<SloppyHandler>();
+        service.register_handler::<SloppyHandler>();
+
+        // Every handler of a shared kind may answer, as long as one of them does
+        let kinds = input(&mut service, PING)
+            .into_iter()
+            .map(|msg| msg.body.content.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec!["pong", "pong"]);
+
+        let replies = input(
+            &mut service,
+            r#"{"src":"c1","dest":"n1","body":{"type":"silent","msg_id":2}}"#,
+        );
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].kind(), "error");
+
+        // Only one handler's replies are kept under FirstSuccess, so the kind isn't shared anymore
+        service.set_dispatch_policy(DispatchPolicy::FirstSuccess);
+        assert_eq!(input(&mut service, PING).len(), 1);
+        let replies = input(
+            &mut service,
+            r#"{"src":"c1","dest":"n1","body":{"type":"twice","msg_id":3}}"#,
+        );
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].kind(), "error");
+    }
+
+    #[derive(Default)]
+    struct QueryHandler;
+
+    impl MessageHandler for QueryHandler {
+        fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+            ["query"].into_iter()
+        }
+
+        fn handle(&mut self, ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+            let answer = ctx.defer_reply()?;
+            ctx.rpc("lin-kv", "read", &(), move |reply| {
+                let value = reply.message_content::<serde_json::Value>()?["value"].clone();
+                answer.reply("query_ok", &serde_json::json!({ "value": value }))
+            })?;
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_reply_routing() {
+        let mut service = MaelstromService::new();
+        service.register_handler::<QueryHandler>();
+
+        let read = input(
+            &mut service,
+            r#"{"src":"c1","dest":"n1","body":{"type":"query","msg_id":7}}"#,
+        );
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].kind(), "read");
+        assert_eq!(service.pending_requests().len(), 1);
+        assert_eq!(service.deferred_replies().len(), 1);
+
+        let read_ok = format!(
+            r#"{{"src":"lin-kv","dest":"n1","body":{{"type":"read_ok","msg_id":1,"in_reply_to":{},"value":42}}}}"#,
+            read[0].body.msg_id.unwrap()
+        );
+        assert!(input(&mut service, &read_ok).is_empty());
+
+        let answer = service.drain_outbox().next().unwrap();
+        assert_eq!(answer.kind(), "query_ok");
+        assert_eq!(answer.dest, Some("c1".to_string()));
+        assert_eq!(answer.body.in_reply_to, Some(7));
+        assert_eq!(
+            answer.body.content.data.get("value"),
+            Some(&serde_json::Value::from(42))
+        );
+        assert!(service.deferred_replies().is_empty());
+
+        // The same reply again is stray now, and is neither handled nor answered
+        assert!(input(&mut service, &read_ok).is_empty());
+        assert_eq!(service.drain_outbox().count(), 0);
+    }
+
+    #[test]
+    fn test_rpc_timeout() {
+        let mut service = MaelstromService::new();
+        service.set_rpc_timeout(Duration::from_secs(1));
+        input(&mut service, INIT);
+
+        let code = Arc::new(Mutex::new(None));
+        let seen = code.clone();
+        service
+            .outbox()
+            .rpc("lin-kv", "read", &(), move |reply| {
+                *seen.lock().unwrap() = Some(reply.message_content::<ErrorMessage>()?.code());
+                Ok(())
+            })
+            .unwrap();
+
+        let now = Instant::now();
+        assert_eq!(service.tick(now).count(), 0);
+        assert_eq!(*code.lock().unwrap(), None);
+
+        assert_eq!(service.tick(now + Duration::from_secs(2)).count(), 0);
+        assert_eq!(*code.lock().unwrap(), Some(usize::from(ErrorKind::Timeout)));
+        assert!(service.pending_requests().is_empty());
+    }
+
+    #[test]
+    fn test_middleware_outgoing_paths() {
+        let mut service = MaelstromService::new();
+        service.register_handler::<QueryHandler>();
+        service.register_middleware::<TaggingMiddleware>();
+        service.set_pre_init_policy(PreInitPolicy::Reject);
+
+        let tagged = |msg: &Message| msg.body.content.data.contains_key("tagged");
+
+        let rejected = input(&mut service, PING);
+        assert_eq!(rejected[0].kind(), "error");
+        assert!(tagged(&rejected[0]));
+
+        let init_ok = input(&mut service, INIT);
+        assert!(tagged(&init_ok[0]));
+
+        service.outbox().send("n2", "gossip", &()).unwrap();
+        assert!(tagged(&service.drain_outbox().next().unwrap()));
+
+        let read = input(
+            &mut service,
+            r#"{"src":"c1","dest":"n1","body":{"type":"query","msg_id":7}}"#,
+        );
+        let read_ok = format!(
+            r#"{{"src":"lin-kv","dest":"n1","body":{{"type":"read_ok","msg_id":1,"in_reply_to":{},"value":42}}}}"#,
+            read[0].body.msg_id.unwrap()
+        );
+        input(&mut service, &read_ok);
+        let answer = service.drain_outbox().next().unwrap();
+        assert_eq!(answer.kind(), "query_ok");
+        assert!(tagged(&answer));
+    }
+
+    #[derive(Default)]
+    struct OfflineMiddleware;
+
+    impl Middleware for OfflineMiddleware {
+        fn outgoing(&mut self, msg: Message) -> Option<Message> {
+            (msg.dest.as_deref() != Some("lin-kv")).then_some(msg)
+        }
+    }
+
+    #[test]
+    fn test_dropped_requests_are_forgotten() {
+        let mut service = MaelstromService::new();
+        service.register_handler::<QueryHandler>();
+        service.register_middleware::<OfflineMiddleware>();
+
+        let replies = input(
+            &mut service,
+            r#"{"src":"c1","dest":"n1","body":{"type":"query","msg_id":7}}"#,
+        );
+
+        assert!(replies.is_empty());
+        assert!(service.pending_requests().is_empty());
+
+        // The deferred reply went down with the request's callback, so the client gets an error instead
+        let error = service.drain_outbox().next().unwrap();
+        assert_eq!(error.kind(), "error");
+        assert_eq!(error.body.in_reply_to, Some(7));
+        assert!(service.deferred_replies().is_empty());
+    }
+
+    #[test]
+    fn test_message_ids() {
+        let mut service = MaelstromService::with_message_ids(MessageIdAllocator::new(10));
+        service.register_handler::<PingHandler>();
+
+        let ping = r#"{"src":"c1","dest":"n1","body":{"type":"ping","msg_id":1}}"#;
+
+        let ids = (0..2)
+            .flat_map(|_| input(&mut service, ping))
+            .map(|msg| msg.body.msg_id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![Some(10), Some(11)]);
+
+        service.outbox().send("n2", "gossip", &()).unwrap();
+        assert_eq!(service.drain_outbox().next().unwrap().body.msg_id, Some(12));
+
+        service.message_ids().reset();
+
+        let ids = input(&mut service, ping)
+            .into_iter()
+            .map(|msg| msg.body.msg_id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![Some(10)]);
+    }
+
+    #[test]
+    fn test_worker_pool() {
+        let mut service = MaelstromService::new();
+        service.register_concurrent_handler::<PingHandler>();
+        service.enable_worker_pool(2);
+
+        let replies = input(
+            &mut service,
+            r#"{"src":"c1","dest":"n1","body":{"type":"ping","msg_id":1}}"#,
+        );
+        assert!(replies.is_empty());
+
+        service.join_worker_pool();
+
+        let replies = service.drain_outbox().collect::<Vec<_>>();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].kind(), "pong");
+        assert_eq!(replies[0].dest, Some("c1".to_string()));
+        assert_eq!(replies[0].body.in_reply_to, Some(1));
+    }
+
+    struct GatedHandler {
+        gate: std::sync::mpsc::Receiver<()>,
+    }
+
+    impl MessageHandler for GatedHandler {
+        fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+            ["ping"].into_iter()
+        }
+
+        fn handle(&mut self, _ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+            let _ = self.gate.recv();
+            Response::new("pong", &()).map(Some)
+        }
+    }
+
+    #[test]
+    fn test_worker_pool_deduplication() {
+        let (open, gate) = std::sync::mpsc::channel();
+
+        let mut service = MaelstromService::new();
+        service.register_concurrent_handler_instance(GatedHandler { gate });
+        service.enable_deduplication(DeduplicationConfig::default());
+        service.enable_worker_pool(2);
+
+        // The duplicate arrives while the original is still blocked on the pool
+        assert!(input(&mut service, PING).is_empty());
+        assert!(input(&mut service, PING).is_empty());
+
+        open.send(()).unwrap();
+        open.send(()).unwrap();
+        service.join_worker_pool();
+
+        let replies = service.drain_outbox().collect::<Vec<_>>();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].kind(), "pong");
+    }
+
+    #[derive(Default)]
+    struct InternalHandler;
+
+    impl MessageHandler for InternalHandler {
+        fn get_handled_messages(&self) -> impl Iterator<Item = &str> {
+            ["internal_*"].into_iter()
+        }
+
+        fn handle(&mut self, ctx: &MessageContext) -> Result<Option<Response>, ErrorMessage> {
+            Response::new(&format!("{}_ok", ctx.message_kind()), &()).map(Some)
+        }
+    }
+
+    #[test]
+    fn test_worker_pool_patterns() {
+        let mut service = MaelstromService::new();
+        service.register_concurrent_handler::<InternalHandler>();
+        service.enable_worker_pool(2);
+
+        let replies = input(
+            &mut service,
+            r#"{"src":"n2","dest":"n1","body":{"type":"internal_sync","msg_id":1}}"#,
+        );
+        assert!(replies.is_empty());
+
+        service.join_worker_pool();
+
+        let replies = service.drain_outbox().collect::<Vec<_>>();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].kind(), "internal_sync_ok");
+    }
+
+    #[test]
+    fn test_worker_pool_disabled() {
+        let mut service = MaelstromService::new();
+        service.register_concurrent_handler::<PingHandler>();
+
+        let replies = input(
+            &mut service,
+            r#"{"src":"c1","dest":"n1","body":{"type":"ping","msg_id":1}}"#,
+        );
 
-        let init_msg = ctx.message_content::<InitMessage>().unwrap();
-        self.handler.handle_init(&init_msg, ctx)
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].kind(), "pong");
+        assert_eq!(service.drain_outbox().count(), 0);
     }
 }
@@ -0,0 +1,50 @@
+use std::{
+    process::ExitCode,
+    thread,
+    time::{Duration, Instant},
+};
+
+const FINISH_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// What was left undone when the node shut down, see `MaelstromService::shutdown`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    pub skipped_hooks: usize, // Handlers whose `on_shutdown` didn't run because the deadline had passed.
+    pub failed_hooks: bool,   // Whether any `on_shutdown` returned an error.
+    pub unhandled_messages: usize, // Messages buffered before `init` that were never handled.
+    pub unanswered_requests: usize, // Requests sent by this node that are still waiting for a reply.
+    pub unsent_replies: usize,      // Requests to this node whose deferred reply was never sent.
+    pub flushed: bool, // Whether in-flight work finished and all output was written before the deadline.
+}
+
+impl ShutdownReport {
+    pub fn is_clean(&self) -> bool {
+        self.skipped_hooks == 0
+            && !self.failed_hooks
+            && self.unhandled_messages == 0
+            && self.unanswered_requests == 0
+            && self.unsent_replies == 0
+            && self.flushed
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        if self.is_clean() {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Waits for `is_finished` to hold until `deadline`, and returns whether it did.
+pub fn wait_until(deadline: Instant, mut is_finished: impl FnMut() -> bool) -> bool {
+    loop {
+        if is_finished() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(FINISH_POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,72 @@
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::protocol::Message;
+
+use super::shutdown::wait_until;
+
+const CLOSE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+pub fn write_message<W>(out: &mut W, msg: &Message) -> io::Result<()>
+where
+    W: Write,
+{
+    let ser = serde_json::to_string(msg)?;
+    writeln!(out, "{}", ser)?;
+    out.flush()
+}
+
+pub struct OutboxWriter {
+    handle: JoinHandle<io::Result<()>>,
+    closing: Arc<AtomicBool>,
+}
+
+impl OutboxWriter {
+    pub(super) fn spawn<W>(rx: Receiver<Message>, mut out: W) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        let closing = Arc::new(AtomicBool::new(false));
+        let closing_flag = closing.clone();
+
+        let handle = thread::spawn(move || loop {
+            match rx.recv_timeout(CLOSE_POLL_INTERVAL) {
+                Ok(msg) => write_message(&mut out, &msg)?,
+                Err(RecvTimeoutError::Timeout) if closing_flag.load(Ordering::Acquire) => {
+                    return Ok(())
+                }
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        });
+
+        Self { handle, closing }
+    }
+
+    /// Writes out everything already posted to the outbox and stops the writer thread.
+    pub fn finish(self) -> io::Result<()> {
+        self.closing.store(true, Ordering::Release);
+        self.handle
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("outbox writer panicked")))
+    }
+
+    /// Like `finish`, but gives up on the writer if it hasn't caught up by `deadline`. Returns whether everything
+    /// posted to the outbox has been written.
+    pub fn finish_before(self, deadline: Instant) -> io::Result<bool> {
+        self.closing.store(true, Ordering::Release);
+        if wait_until(deadline, || self.handle.is_finished()) {
+            self.finish().map(|_| true)
+        } else {
+            Ok(false)
+        }
+    }
+}
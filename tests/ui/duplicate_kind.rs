@@ -0,0 +1,10 @@
+use gossip_glomers_rust::protocol::MaelstromMessage;
+
+#[derive(MaelstromMessage)]
+enum Request {
+    Read,
+    #[maelstrom(kind = "read")]
+    ReadAgain,
+}
+
+fn main() {}
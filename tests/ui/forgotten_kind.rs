@@ -0,0 +1,18 @@
+use gossip_glomers_rust::protocol::MaelstromMessage;
+
+#[derive(MaelstromMessage)]
+enum Request {
+    Read,
+    Write { value: usize },
+}
+
+// Handlers match the decoded request exhaustively, so a kind added to the enum can't be forgotten
+fn handle(request: Request) -> &'static str {
+    match request {
+        Request::Read => "read_ok",
+    }
+}
+
+fn main() {
+    let _ = handle;
+}